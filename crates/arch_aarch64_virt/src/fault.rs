@@ -0,0 +1,52 @@
+//! Synchronous exceptions taken by kernel-scheduled threads.
+
+use core::arch::asm;
+
+use kernel::{FaultAccess, FaultInfo};
+
+use super::preempt::Context;
+
+// ESR_EL1.EC for an SVC from AArch64: `ThreadArch::yield_now`.
+const EC_SVC64: u8 = 0x15;
+// ESR_EL1.EC values for instruction and data aborts (from a lower EL / the current EL).
+const EC_IABT_LOWER: u8 = 0x20;
+const EC_IABT_CUR: u8 = 0x21;
+const EC_DABT_LOWER: u8 = 0x24;
+const EC_DABT_CUR: u8 = 0x25;
+
+// ESR_EL1.ISS.WnR: the data abort was caused by a write.
+const ESR_WNR: u64 = 1 << 6;
+
+/// Called by `exc_sync` in boot.S with the trapping thread's saved context.
+///
+/// An `svc` is a yield and goes to the kernel's scheduler. Aborts are reported to the
+/// kernel, which forwards them to the thread's pager and leaves the thread suspended.
+/// Returns the context to run next, or null if the exception is fatal (not an abort, a
+/// thread with no pager, or no other thread to run); boot.S then prints ESR/ELR/FAR and
+/// hangs.
+#[unsafe(no_mangle)]
+pub extern "C" fn rust_sync_handler(current: *mut Context) -> *const Context {
+    let (esr, far): (u64, u64);
+    unsafe {
+        asm!("mrs {}, esr_el1", out(reg) esr, options(nomem, nostack, preserves_flags));
+        asm!("mrs {}, far_el1", out(reg) far, options(nomem, nostack, preserves_flags));
+    }
+
+    let class = ((esr >> 26) & 0x3F) as u8;
+    if class == EC_SVC64 {
+        // ELR already points past the `svc`.
+        return kernel::thread_yield(current as usize) as *const Context;
+    }
+    let access = match class {
+        EC_IABT_LOWER | EC_IABT_CUR => FaultAccess::Exec,
+        EC_DABT_LOWER | EC_DABT_CUR if esr & ESR_WNR != 0 => FaultAccess::Write,
+        EC_DABT_LOWER | EC_DABT_CUR => FaultAccess::Read,
+        _ => return core::ptr::null(),
+    };
+    let fault = FaultInfo {
+        addr: far,
+        access,
+        class,
+    };
+    kernel::thread_fault(fault).map_or(core::ptr::null(), |next| next as *const Context)
+}
@@ -46,7 +46,11 @@ impl hal::log::Logger for UartLogger {
 
 mod timer;
 mod preempt;
+#[cfg(any(feature = "demo-ipc", feature = "demo-preempt", feature = "demo-memory"))]
 mod mem;
+#[cfg(any(feature = "demo-ipc", feature = "demo-memory"))]
+mod mmu;
+mod fault;
 
 #[unsafe(no_mangle)]
 pub extern "C" fn rust_main() -> ! {
@@ -56,7 +60,21 @@ pub extern "C" fn rust_main() -> ! {
     #[cfg(feature = "demo-ipc")]
     {
         logger.log("rustOS: IPC + cooperative scheduling demo\n");
-        kernel::kmain(&logger)
+        // Page tables are needed to map IPC page grants into receivers.
+        mmu::init();
+        kernel::set_grant_mapper(&mmu::GRANTS);
+        kernel::set_tick_source(timer::ticks);
+        kernel::set_irq_controller(&timer::GIC);
+        // The task scheduler runs as a thread, like the preemption demo's threads.
+        kernel::set_thread_arch(&preempt::ARCH);
+        kernel::set_frame_allocator(&mem::KERNEL_FRAMES);
+        kernel::spawn_thread(ipc_thread, 0, preempt::STACK_SIZE, 0)
+            .expect("ipc: no thread slot");
+        // A thread that faults on purpose: its fault goes to the kernel's pager task.
+        let faulty = kernel::spawn_thread(faulty_thread, 0, preempt::STACK_SIZE, 0)
+            .expect("ipc: no thread slot");
+        kernel::supervise_thread(faulty).expect("ipc: no task for the faulting thread");
+        kernel::start_threads()
     }
 
     #[cfg(feature = "demo-timer")]
@@ -72,11 +90,9 @@ pub extern "C" fn rust_main() -> ! {
     #[cfg(feature = "demo-preempt")]
     {
         logger.log("rustOS: preemptive multitasking demo\n");
+        mem::init_frames();
         preempt::init();
-        extern "C" {
-            fn start_first(ctx: *const preempt::Context) -> !;
-        }
-        unsafe { start_first(preempt::first_context()) }
+        kernel::start_threads()
     }
 
     #[cfg(feature = "demo-memory")]
@@ -97,6 +113,27 @@ pub extern "C" fn rust_main() -> ! {
     }
 }
 
+#[cfg(feature = "demo-ipc")]
+extern "C" fn ipc_thread(_arg: usize) -> ! {
+    // The timer tick wakes the scheduler from `halt`, drives IPC deadlines and preempts.
+    // Start it from a thread so the first interrupt has a context to save into.
+    timer::init();
+    kernel::kmain(&UartLogger)
+}
+
+// Nothing is mapped from 3GB up (see `mmu::build_tables`).
+#[cfg(feature = "demo-ipc")]
+const UNMAPPED_VA: usize = 0xC000_0000;
+
+#[cfg(feature = "demo-ipc")]
+extern "C" fn faulty_thread(_arg: usize) -> ! {
+    // Let ping and pong get going first.
+    kernel::sleep_ticks(30);
+    UartLogger::puts("faulty: reading unmapped memory\n");
+    unsafe { core::ptr::read_volatile(UNMAPPED_VA as *const u64) };
+    unreachable!("faulty: the pager resumed a thread it can't fix")
+}
+
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
     UartLogger::puts("rustOS: PANIC\n");
@@ -1,13 +1,21 @@
+#[cfg(feature = "demo-memory")]
 use core::ptr::{read_volatile, write_volatile};
 
+#[cfg(feature = "demo-memory")]
+use super::mmu::{build_tables, enable_mmu, GRANTS};
+#[cfg(any(
+    feature = "demo-ipc",
+    feature = "demo-preempt",
+    feature = "demo-memory"
+))]
 use super::UartLogger;
 
 // QEMU virt RAM (we force -m 256M in the run script)
-const RAM_START: u64 = 0x4000_0000;
-const RAM_SIZE: u64 = 256 * 1024 * 1024;
+pub const RAM_START: u64 = 0x4000_0000;
+pub const RAM_SIZE: u64 = 256 * 1024 * 1024;
 const RAM_END: u64 = RAM_START + RAM_SIZE;
 
-const PAGE_SIZE: u64 = 4096;
+pub const PAGE_SIZE: u64 = 4096;
 
 extern "C" {
     static __stack_top: u8;
@@ -18,119 +26,102 @@ fn align_up(x: u64, align: u64) -> u64 {
     (x + align - 1) & !(align - 1)
 }
 
+// Freed runs remembered for reuse. Adjacent runs are merged, so this bounds how
+// fragmented freed memory can get; a free that would need another slot is leaked.
+const MAX_FREE_RUNS: usize = 16;
+
 struct FrameAlloc {
     next: u64,
     end: u64,
+    // (first frame, frame count) of runs handed back with `free`; count 0 is unused.
+    free: [(u64, u64); MAX_FREE_RUNS],
 }
 
 impl FrameAlloc {
-    fn new(start: u64, end: u64) -> Self {
-        Self { next: start, end }
+    const fn new(start: u64, end: u64) -> Self {
+        Self {
+            next: start,
+            end,
+            free: [(0, 0); MAX_FREE_RUNS],
+        }
     }
 
+    #[cfg(feature = "demo-memory")]
     fn alloc(&mut self) -> Option<u64> {
+        self.alloc_contig(1)
+    }
+
+    fn alloc_contig(&mut self, count: u64) -> Option<u64> {
+        // First fit among freed runs, splitting off the front of a larger one.
+        if let Some(run) = self.free.iter_mut().find(|r| r.1 >= count && count > 0) {
+            let p = run.0;
+            run.0 += count * PAGE_SIZE;
+            run.1 -= count;
+            return Some(p);
+        }
         let p = self.next;
-        if p + PAGE_SIZE > self.end {
+        let size = count * PAGE_SIZE;
+        if p + size > self.end {
             return None;
         }
-        self.next += PAGE_SIZE;
+        self.next += size;
         Some(p)
     }
-}
-
-// AArch64 4k-page tables.
-#[repr(align(4096))]
-struct PageTable {
-    entries: [u64; 512],
-}
 
-impl PageTable {
-    const fn new() -> Self {
-        Self { entries: [0; 512] }
+    /// Hand back `count` frames from `phys`, merging them with any freed run they touch
+    /// and with the unallocated space at `next`. Returns false, leaking the frames, if
+    /// every free-run slot is taken.
+    #[cfg(any(feature = "demo-ipc", feature = "demo-preempt"))]
+    fn free(&mut self, phys: u64, count: u64) -> bool {
+        if count == 0 {
+            return true;
+        }
+        let (mut start, mut end) = (phys, phys + count * PAGE_SIZE);
+        // Runs are kept merged, so at most one ends at `start` and one begins at `end`.
+        for run in self.free.iter_mut().filter(|r| r.1 > 0) {
+            let run_end = run.0 + run.1 * PAGE_SIZE;
+            if run_end == start {
+                start = run.0;
+                *run = (0, 0);
+            } else if run.0 == end {
+                end = run_end;
+                *run = (0, 0);
+            }
+        }
+        if end == self.next {
+            self.next = start;
+            return true;
+        }
+        match self.free.iter_mut().find(|r| r.1 == 0) {
+            Some(run) => {
+                *run = (start, (end - start) / PAGE_SIZE);
+                true
+            }
+            None => false,
+        }
     }
 }
 
-extern "C" {
-    fn enable_mmu(ttbr0: u64);
-}
-
-// Descriptor bits (4k granule).
-const DESC_VALID: u64 = 1 << 0;
-const DESC_TABLE: u64 = 1 << 1; // when valid and bit1=1 => table/page
-const DESC_BLOCK: u64 = 0 << 1; // when valid and bit1=0 => block
-
-const AF: u64 = 1 << 10;
-const SH_INNER: u64 = 0b11 << 8;
-const ATTRIDX0: u64 = 0 << 2; // normal memory
-const ATTRIDX1: u64 = 1 << 2; // device memory
-
-// Device should be XN.
-const PXN: u64 = 1 << 53;
-const UXN: u64 = 1 << 54;
-
-static mut TT_L0: PageTable = PageTable::new();
-static mut TT_L1: PageTable = PageTable::new();
-static mut TT_L2_0: PageTable = PageTable::new(); // VA 0..1GB (UART)
-static mut TT_L2_1: PageTable = PageTable::new(); // VA 1..2GB (RAM)
-static mut TT_L2_2: PageTable = PageTable::new(); // VA 2..3GB (test VA)
-static mut TT_L3_TEST: PageTable = PageTable::new();
-
-fn build_tables(frame0: u64) -> (u64, u64) {
-    // We'll map a test VA in the low VA space so TTBR0 can translate it.
-    let test_va: u64 = 0x8000_0000; // 2GB
+// Global frame allocator, set up by `init_frames` once we know where the kernel ends.
+static mut FRAMES: FrameAlloc = FrameAlloc::new(0, 0);
 
-    unsafe {
-        TT_L0.entries = [0; 512];
-        TT_L1.entries = [0; 512];
-        TT_L2_0.entries = [0; 512];
-        TT_L2_1.entries = [0; 512];
-        TT_L2_2.entries = [0; 512];
-        TT_L3_TEST.entries = [0; 512];
-
-        // L0[0] -> L1 (covers low VA range)
-        TT_L0.entries[0] = (&raw const TT_L1 as *const _ as u64) | DESC_VALID | DESC_TABLE;
-
-        // L1[0] (0..1GB) -> L2_0
-        TT_L1.entries[0] = (&raw const TT_L2_0 as *const _ as u64) | DESC_VALID | DESC_TABLE;
-        // L1[1] (1..2GB) -> L2_1 (RAM at 0x4000_0000)
-        TT_L1.entries[1] = (&raw const TT_L2_1 as *const _ as u64) | DESC_VALID | DESC_TABLE;
-        // L1[2] (2..3GB) -> L2_2 (test VA)
-        TT_L1.entries[2] = (&raw const TT_L2_2 as *const _ as u64) | DESC_VALID | DESC_TABLE;
-
-        // Map UART 0x0900_0000 as a 2MB device block (identity).
-        let uart_va: u64 = 0x0900_0000;
-        let uart_l2 = ((uart_va >> 21) & 0x1FF) as usize;
-        TT_L2_0.entries[uart_l2] =
-            (uart_va & 0xFFFF_FFFF_FFE0_0000) | DESC_VALID | DESC_BLOCK | ATTRIDX1 | AF | PXN | UXN;
-
-        // Map RAM 0x4000_0000..0x5000_0000 as 2MB blocks (identity), normal memory.
-        let blocks = RAM_SIZE / (2 * 1024 * 1024);
-        for i in 0..blocks {
-            let va = RAM_START + i * 2 * 1024 * 1024;
-            let pa = va;
-            let idx = ((va >> 21) & 0x1FF) as usize; // within L2_1
-            TT_L2_1.entries[idx] =
-                (pa & 0xFFFF_FFFF_FFE0_0000) | DESC_VALID | DESC_BLOCK | ATTRIDX0 | AF | SH_INNER;
-        }
-
-        // Map test_va -> frame0 as a single 4k page:
-        // L2 entry points to L3 table.
-        let test_l2 = ((test_va >> 21) & 0x1FF) as usize;
-        TT_L2_2.entries[test_l2] =
-            (&raw const TT_L3_TEST as *const _ as u64) | DESC_VALID | DESC_TABLE;
-        let test_l3 = ((test_va >> 12) & 0x1FF) as usize;
-        TT_L3_TEST.entries[test_l3] = (frame0 & 0xFFFF_FFFF_FFFF_F000)
-            | DESC_VALID
-            | DESC_TABLE
-            | ATTRIDX0
-            | AF
-            | SH_INNER;
-    }
+/// Allocate `count` physically contiguous frames (e.g. to back an IPC page grant).
+#[cfg(any(feature = "demo-ipc", feature = "demo-preempt"))]
+pub fn alloc_frames(count: usize) -> Option<u64> {
+    let frames = &raw mut FRAMES;
+    unsafe { (*frames).alloc_contig(count as u64) }
+}
 
-    let ttbr0 = &raw const TT_L0 as *const _ as u64;
-    (ttbr0, test_va)
+/// Set up the frame allocator over the RAM after the kernel image. Returns where the
+/// kernel ends. `mmu::init` and `demo` do this themselves.
+pub fn init_frames() -> u64 {
+    let kernel_end = unsafe { &__stack_top as *const u8 as u64 };
+    let free_start = align_up(kernel_end, PAGE_SIZE);
+    unsafe { FRAMES = FrameAlloc::new(free_start, RAM_END) };
+    kernel_end
 }
 
+#[cfg(feature = "demo-memory")]
 fn put_hex(prefix: &str, v: u64) {
     UartLogger::puts(prefix);
     // very small hex printer
@@ -149,21 +140,44 @@ fn put_hex(prefix: &str, v: u64) {
     UartLogger::puts("\n");
 }
 
+/// Hands frames to the kernel, e.g. for thread stacks. RAM is identity mapped.
+#[cfg(any(feature = "demo-ipc", feature = "demo-preempt"))]
+pub struct KernelFrames;
+
+#[cfg(any(feature = "demo-ipc", feature = "demo-preempt"))]
+pub static KERNEL_FRAMES: KernelFrames = KernelFrames;
+
+#[cfg(any(feature = "demo-ipc", feature = "demo-preempt"))]
+impl hal::mem::FrameAllocator for KernelFrames {
+    fn alloc_frames(&self, count: usize) -> Option<u64> {
+        hal::arch::without_interrupts(|| alloc_frames(count))
+    }
+
+    fn free_frames(&self, phys: u64, count: usize) {
+        let frames = &raw mut FRAMES;
+        let kept = hal::arch::without_interrupts(|| unsafe { (*frames).free(phys, count as u64) });
+        if !kept {
+            UartLogger::puts("mm: free run table full, leaking frames\n");
+        }
+    }
+}
+
+#[cfg(feature = "demo-memory")]
 pub fn demo() {
     UartLogger::puts("mm: demo start\n");
 
-    let kernel_end = unsafe { &__stack_top as *const u8 as u64 };
+    let kernel_end = init_frames();
     let free_start = align_up(kernel_end, PAGE_SIZE);
 
     put_hex("mm: kernel_end=0x", kernel_end);
     put_hex("mm: free_start=0x", free_start);
     put_hex("mm: ram_end=0x", RAM_END);
 
-    let mut fa = FrameAlloc::new(free_start, RAM_END);
+    let fa = &raw mut FRAMES;
 
     // Allocate a few frames and write/read patterns.
-    let f0 = fa.alloc().expect("no frame");
-    let f1 = fa.alloc().expect("no frame");
+    let f0 = unsafe { (*fa).alloc() }.expect("no frame");
+    let f1 = unsafe { (*fa).alloc() }.expect("no frame");
     put_hex("mm: frame0=0x", f0);
     put_hex("mm: frame1=0x", f1);
 
@@ -192,6 +206,14 @@ pub fn demo() {
         put_hex("mm: test_va_read=0x", r);
     }
 
+    // Map frame1 the way an IPC page grant would and read the earlier pattern back.
+    use hal::mem::GrantMapper;
+    if let Some(va) = GRANTS.map_grant(0, f1, 1) {
+        put_hex("mm: grant_va=0x", va);
+        let r = unsafe { read_volatile(va as *const u32) } as u64;
+        put_hex("mm: grant_read=0x", r);
+    }
+
     UartLogger::puts("mm: demo done (MMU is ON)\n");
 }
 
@@ -0,0 +1,172 @@
+//! Translation tables for QEMU virt: identity-mapped RAM, UART and GIC, a test page and
+//! the windows IPC page grants are mapped into.
+
+use super::mem::{PAGE_SIZE, RAM_SIZE, RAM_START};
+
+// AArch64 4k-page tables.
+#[repr(align(4096))]
+struct PageTable {
+    entries: [u64; 512],
+}
+
+impl PageTable {
+    const fn new() -> Self {
+        Self { entries: [0; 512] }
+    }
+}
+
+extern "C" {
+    pub fn enable_mmu(ttbr0: u64);
+}
+
+// Descriptor bits (4k granule).
+const DESC_VALID: u64 = 1 << 0;
+const DESC_TABLE: u64 = 1 << 1; // when valid and bit1=1 => table/page
+const DESC_BLOCK: u64 = 0 << 1; // when valid and bit1=0 => block
+
+const AF: u64 = 1 << 10;
+const SH_INNER: u64 = 0b11 << 8;
+const ATTRIDX0: u64 = 0 << 2; // normal memory
+const ATTRIDX1: u64 = 1 << 2; // device memory
+
+// Device should be XN.
+const PXN: u64 = 1 << 53;
+const UXN: u64 = 1 << 54;
+
+static mut TT_L0: PageTable = PageTable::new();
+static mut TT_L1: PageTable = PageTable::new();
+static mut TT_L2_0: PageTable = PageTable::new(); // VA 0..1GB (GIC, UART)
+static mut TT_L2_1: PageTable = PageTable::new(); // VA 1..2GB (RAM)
+static mut TT_L2_2: PageTable = PageTable::new(); // VA 2..3GB (test VA)
+static mut TT_L3_TEST: PageTable = PageTable::new();
+
+// Page grant windows. Until tasks get their own translation tables, each task's "address
+// space" for IPC grants is a private 2MB window just above the test page, backed by its
+// own L3 table. There is one per task the kernel can run, all inside `TT_L2_2`. Windows
+// are filled bump-style and never unmapped.
+const GRANT_BASE: u64 = 0x8020_0000;
+const GRANT_WINDOW: u64 = 2 * 1024 * 1024;
+const MAX_GRANT_SPACES: usize = kernel::MAX_TASKS;
+const _: () = assert!(GRANT_BASE + MAX_GRANT_SPACES as u64 * GRANT_WINDOW <= 0xC000_0000);
+
+static mut TT_L3_GRANT: [PageTable; MAX_GRANT_SPACES] =
+    [const { PageTable::new() }; MAX_GRANT_SPACES];
+static mut GRANT_USED: [u64; MAX_GRANT_SPACES] = [0; MAX_GRANT_SPACES]; // pages per window
+
+pub fn build_tables(frame0: u64) -> (u64, u64) {
+    // We'll map a test VA in the low VA space so TTBR0 can translate it.
+    let test_va: u64 = 0x8000_0000; // 2GB
+
+    unsafe {
+        TT_L0.entries = [0; 512];
+        TT_L1.entries = [0; 512];
+        TT_L2_0.entries = [0; 512];
+        TT_L2_1.entries = [0; 512];
+        TT_L2_2.entries = [0; 512];
+        TT_L3_TEST.entries = [0; 512];
+
+        // L0[0] -> L1 (covers low VA range)
+        TT_L0.entries[0] = (&raw const TT_L1 as *const _ as u64) | DESC_VALID | DESC_TABLE;
+
+        // L1[0] (0..1GB) -> L2_0
+        TT_L1.entries[0] = (&raw const TT_L2_0 as *const _ as u64) | DESC_VALID | DESC_TABLE;
+        // L1[1] (1..2GB) -> L2_1 (RAM at 0x4000_0000)
+        TT_L1.entries[1] = (&raw const TT_L2_1 as *const _ as u64) | DESC_VALID | DESC_TABLE;
+        // L1[2] (2..3GB) -> L2_2 (test VA)
+        TT_L1.entries[2] = (&raw const TT_L2_2 as *const _ as u64) | DESC_VALID | DESC_TABLE;
+
+        // Map UART 0x0900_0000 as a 2MB device block (identity).
+        let uart_va: u64 = 0x0900_0000;
+        let uart_l2 = ((uart_va >> 21) & 0x1FF) as usize;
+        TT_L2_0.entries[uart_l2] =
+            (uart_va & 0xFFFF_FFFF_FFE0_0000) | DESC_VALID | DESC_BLOCK | ATTRIDX1 | AF | PXN | UXN;
+
+        // Map the GICv2 distributor and CPU interface (0x0800_0000, 0x0801_0000) as one
+        // 2MB device block (identity); the timer and IRQ masking need them with the MMU on.
+        let gic_va: u64 = 0x0800_0000;
+        let gic_l2 = ((gic_va >> 21) & 0x1FF) as usize;
+        TT_L2_0.entries[gic_l2] =
+            (gic_va & 0xFFFF_FFFF_FFE0_0000) | DESC_VALID | DESC_BLOCK | ATTRIDX1 | AF | PXN | UXN;
+
+        // Map RAM 0x4000_0000..0x5000_0000 as 2MB blocks (identity), normal memory.
+        let blocks = RAM_SIZE / (2 * 1024 * 1024);
+        for i in 0..blocks {
+            let va = RAM_START + i * 2 * 1024 * 1024;
+            let pa = va;
+            let idx = ((va >> 21) & 0x1FF) as usize; // within L2_1
+            TT_L2_1.entries[idx] =
+                (pa & 0xFFFF_FFFF_FFE0_0000) | DESC_VALID | DESC_BLOCK | ATTRIDX0 | AF | SH_INNER;
+        }
+
+        // Map test_va -> frame0 as a single 4k page:
+        // L2 entry points to L3 table.
+        let test_l2 = ((test_va >> 21) & 0x1FF) as usize;
+        TT_L2_2.entries[test_l2] =
+            (&raw const TT_L3_TEST as *const _ as u64) | DESC_VALID | DESC_TABLE;
+        let test_l3 = ((test_va >> 12) & 0x1FF) as usize;
+        TT_L3_TEST.entries[test_l3] = (frame0 & 0xFFFF_FFFF_FFFF_F000)
+            | DESC_VALID
+            | DESC_TABLE
+            | ATTRIDX0
+            | AF
+            | SH_INNER;
+
+        // Hook up the (initially empty) grant windows.
+        for i in 0..MAX_GRANT_SPACES {
+            TT_L3_GRANT[i].entries = [0; 512];
+            let va = GRANT_BASE + i as u64 * GRANT_WINDOW;
+            let l2 = ((va >> 21) & 0x1FF) as usize;
+            TT_L2_2.entries[l2] =
+                (&raw const TT_L3_GRANT[i] as *const _ as u64) | DESC_VALID | DESC_TABLE;
+            GRANT_USED[i] = 0;
+        }
+    }
+
+    let ttbr0 = &raw const TT_L0 as *const _ as u64;
+    (ttbr0, test_va)
+}
+
+/// Maps IPC page grants into the receiving task's grant window.
+pub struct Grants;
+
+pub static GRANTS: Grants = Grants;
+
+impl hal::mem::GrantMapper for Grants {
+    fn map_grant(&self, space: usize, phys: u64, frames: usize) -> Option<u64> {
+        if space >= MAX_GRANT_SPACES {
+            return None;
+        }
+        let frames = frames as u64;
+        unsafe {
+            let used = GRANT_USED[space];
+            if used + frames > GRANT_WINDOW / PAGE_SIZE {
+                return None;
+            }
+            let table = &raw mut TT_L3_GRANT[space];
+            for i in 0..frames {
+                (*table).entries[(used + i) as usize] = ((phys + i * PAGE_SIZE)
+                    & 0xFFFF_FFFF_FFFF_F000)
+                    | DESC_VALID
+                    | DESC_TABLE
+                    | ATTRIDX0
+                    | AF
+                    | SH_INNER;
+            }
+            GRANT_USED[space] = used + frames;
+            // These entries were invalid before, so there is nothing stale in the TLB;
+            // just make the table writes visible to the walker before the VA is used.
+            core::arch::asm!("dsb ishst", "isb", options(nostack));
+            Some(GRANT_BASE + space as u64 * GRANT_WINDOW + used * PAGE_SIZE)
+        }
+    }
+}
+
+/// Bring up the frame allocator and page tables and turn the MMU on, without the demo
+/// chatter. Needed before registering `GRANTS` with the kernel.
+#[cfg(feature = "demo-ipc")]
+pub fn init() {
+    super::mem::init_frames();
+    let f0 = super::mem::alloc_frames(1).expect("no frame");
+    let (ttbr0, _) = build_tables(f0);
+    unsafe { enable_mmu(ttbr0) };
+}
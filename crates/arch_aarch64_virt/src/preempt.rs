@@ -1,6 +1,8 @@
 #![allow(dead_code)]
 
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::mem::size_of;
+
+use hal::thread::{ThreadArch, ThreadEntry};
 
 use super::{timer, UartLogger};
 
@@ -12,88 +14,84 @@ pub struct Context {
     pub spsr: u64,
 }
 
-const STACK_SIZE: usize = 16 * 1024;
+pub(crate) const STACK_SIZE: usize = 16 * 1024;
 
-#[repr(align(16))]
-struct Stack([u8; STACK_SIZE]);
+extern "C" {
+    fn start_first(ctx: *const Context) -> !;
+}
 
-static STACK0: Stack = Stack([0; STACK_SIZE]);
-static STACK1: Stack = Stack([0; STACK_SIZE]);
+/// Thread support for the kernel: contexts are `Context` blocks kept at the top of each
+/// thread's stack, saved and restored by `exc_irq`/`exc_sync` in boot.S. Yielding is an
+/// `svc`, which `exc_sync` hands to `kernel::thread_yield`.
+pub struct Arch;
+
+pub static ARCH: Arch = Arch;
+
+impl ThreadArch for Arch {
+    fn init(&self, stack: &'static mut [u8], entry: ThreadEntry, arg: usize) -> usize {
+        let top = stack.as_mut_ptr_range().end as usize;
+        // Keep the block and the stack below it 16-byte aligned, as AArch64 requires.
+        let ctx = (top - size_of::<Context>()) & !0xF;
+        let mut x = [0; 31];
+        x[0] = arg as u64;
+        unsafe {
+            (ctx as *mut Context).write(Context {
+                x,
+                sp: ctx as u64,
+                elr: entry as usize as u64,
+                spsr: 0x5, // EL1h
+            });
+        }
+        ctx
+    }
 
-static mut CTX: [Context; 2] = [
-    Context {
-        x: [0; 31],
-        sp: 0,
-        elr: 0,
-        spsr: 0x5, // EL1h
-    },
-    Context {
-        x: [0; 31],
-        sp: 0,
-        elr: 0,
-        spsr: 0x5, // EL1h
-    },
-];
+    fn start(&self, ctx: usize) -> ! {
+        unsafe { start_first(ctx as *const Context) }
+    }
 
-static CURRENT: AtomicUsize = AtomicUsize::new(0);
+    fn yield_now(&self) {
+        unsafe { core::arch::asm!("svc #0") };
+    }
+}
 
-pub(crate) extern "C" fn thread_a_entry() -> ! {
+pub(crate) extern "C" fn thread_a_entry(_arg: usize) -> ! {
     // Enable the timer after the first thread context is active.
     timer::init();
-    let mut last_tick: u64 = 0;
+    // Print rarely so QEMU escape sequences are usable.
+    // (Ticks are 100ms; print ~once/second.)
+    let mut next = 0;
     loop {
-        // Print rarely so QEMU escape sequences are usable.
-        // (Ticks are 100ms; print ~once/second.)
-        let t = timer::ticks();
-        if t != last_tick {
-            last_tick = t;
-            if (t % 10) == 0 {
-                UartLogger::puts("A\n");
-            }
+        next += 10;
+        kernel::sleep_until(next);
+        UartLogger::puts("A\n");
+        // Every few seconds, a short-lived worker whose stack is reclaimed on exit. It
+        // outranks A and B, so it runs before `spawn_thread` returns.
+        if (next % 30) == 0 && kernel::spawn_thread(worker_entry, 0, STACK_SIZE, 1).is_err() {
+            UartLogger::puts("A: no room for a worker\n");
         }
-        core::hint::spin_loop();
     }
 }
 
-pub(crate) extern "C" fn thread_b_entry() -> ! {
-    let mut last_tick: u64 = 0;
+pub(crate) extern "C" fn thread_b_entry(_arg: usize) -> ! {
+    // Half a second out of phase with A.
+    kernel::sleep_until(5);
     loop {
-        let t = timer::ticks();
-        if t != last_tick {
-            last_tick = t;
-            if (t % 10) == 5 {
-                UartLogger::puts("B\n");
-            }
-        }
-        core::hint::spin_loop();
+        UartLogger::puts("B\n");
+        kernel::sleep_ticks(10);
     }
 }
 
-pub fn init() {
-    unsafe {
-        let top0 = STACK0.0.as_ptr().add(STACK_SIZE) as u64;
-        let top1 = STACK1.0.as_ptr().add(STACK_SIZE) as u64;
-
-        CTX[0].sp = top0;
-        CTX[0].elr = thread_a_entry as *const () as usize as u64;
-        CTX[0].spsr = 0x5;
-
-        CTX[1].sp = top1;
-        CTX[1].elr = thread_b_entry as *const () as usize as u64;
-        CTX[1].spsr = 0x5;
-    }
-}
-
-pub fn first_context() -> *const Context {
-    unsafe { &CTX[0] as *const Context }
+extern "C" fn worker_entry(_arg: usize) -> ! {
+    UartLogger::puts("W\n");
+    kernel::exit_thread()
 }
 
-pub fn switch_next(_current_ctx: *mut Context) -> *const Context {
-    // Round-robin 0 <-> 1
-    let cur = CURRENT.load(Ordering::Relaxed);
-    let next = cur ^ 1;
-    CURRENT.store(next, Ordering::Relaxed);
-    unsafe { &CTX[next] as *const Context }
+/// Hand the two demo threads to the kernel's scheduler.
+#[cfg(feature = "demo-preempt")]
+pub fn init() {
+    kernel::set_thread_arch(&ARCH);
+    kernel::set_tick_source(timer::ticks);
+    kernel::set_frame_allocator(&super::mem::KERNEL_FRAMES);
+    kernel::spawn_thread(thread_a_entry, 0, STACK_SIZE, 0).expect("preempt: no thread slot");
+    kernel::spawn_thread(thread_b_entry, 0, STACK_SIZE, 0).expect("preempt: no thread slot");
 }
-
-
@@ -14,6 +14,7 @@ const GICC_BASE: usize = 0x0801_0000;
 // GICD registers
 const GICD_CTLR: usize = 0x000;
 const GICD_ISENABLER0: usize = 0x100;
+const GICD_ICENABLER0: usize = 0x180;
 const GICD_IPRIORITYR: usize = 0x400;
 const GICD_ITARGETSR: usize = 0x800;
 
@@ -37,18 +38,36 @@ fn mmio_read32(base: usize, off: usize) -> u32 {
 }
 
 fn enable_irq(id: u32) {
-    // Enable SGI/PPI IDs 0..31 in ISENABLER0
-    if id < 32 {
-        let mask = 1u32 << id;
-        mmio_write32(GICD_BASE, GICD_ISENABLER0, mask);
-
-        // Priority for interrupt id
-        let pri_off = GICD_IPRIORITYR + (id as usize);
-        unsafe { core::ptr::write_volatile((GICD_BASE + pri_off) as *mut u8, 0x80) };
-
-        // Target CPU0 (not required for PPIs, but harmless)
-        let tgt_off = GICD_ITARGETSR + (id as usize);
-        unsafe { core::ptr::write_volatile((GICD_BASE + tgt_off) as *mut u8, 0x01) };
+    // One enable bit per ID, 32 IDs per ISENABLERn
+    let mask = 1u32 << (id % 32);
+    mmio_write32(GICD_BASE, GICD_ISENABLER0 + 4 * (id / 32) as usize, mask);
+
+    // Priority for interrupt id
+    let pri_off = GICD_IPRIORITYR + (id as usize);
+    unsafe { core::ptr::write_volatile((GICD_BASE + pri_off) as *mut u8, 0x80) };
+
+    // Target CPU0 (not required for PPIs, but harmless)
+    let tgt_off = GICD_ITARGETSR + (id as usize);
+    unsafe { core::ptr::write_volatile((GICD_BASE + tgt_off) as *mut u8, 0x01) };
+}
+
+fn disable_irq(id: u32) {
+    let mask = 1u32 << (id % 32);
+    mmio_write32(GICD_BASE, GICD_ICENABLER0 + 4 * (id / 32) as usize, mask);
+}
+
+/// The GIC, as used by the kernel to mask lines it has handed to drivers.
+pub struct Gic;
+
+pub static GIC: Gic = Gic;
+
+impl hal::irq::IrqController for Gic {
+    fn mask(&self, irq: u32) {
+        disable_irq(irq);
+    }
+
+    fn unmask(&self, irq: u32) {
+        enable_irq(irq);
     }
 }
 
@@ -99,16 +118,19 @@ pub extern "C" fn rust_irq_handler(current: *mut Context) -> *const Context {
 
     let mut next: *const Context = current;
     if id == IRQ_CNTPNS {
-        let t = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+        TICKS.fetch_add(1, Ordering::Relaxed);
         let freq = CNTFRQ.load(Ordering::Relaxed);
         if freq != 0 {
             program_timer(freq);
         }
 
-        // Switch threads every 5 ticks (~500ms with 100ms tick).
-        if (t % 5) == 0 {
-            next = super::preempt::switch_next(current);
-        }
+        // The kernel decides when the running thread's slice is up (before any thread
+        // has started it just hands `current` back).
+        next = kernel::preempt_tick(current as usize) as *const Context;
+    } else if id < 1020 {
+        // Anything else belongs to a user-level driver, if one is bound (1020+ are
+        // special/spurious IDs).
+        kernel::handle_irq(id, &GIC);
     }
 
     // End of interrupt
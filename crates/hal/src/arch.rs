@@ -1,22 +1,73 @@
 #[inline(always)]
 pub fn halt() {
     // Low-power halt per arch. Keep it tiny and dependency-free.
-    #[cfg(target_arch = "x86_64")]
+    #[cfg(all(target_arch = "x86_64", target_os = "none"))]
     unsafe {
         core::arch::asm!("hlt", options(nomem, nostack, preserves_flags));
     }
 
-    #[cfg(target_arch = "aarch64")]
+    #[cfg(all(target_arch = "aarch64", target_os = "none"))]
     unsafe {
         // Use WFI (wait-for-interrupt) so we reliably sleep until the next IRQ.
         // WFE can return immediately if an event is already latched.
         core::arch::asm!("wfi", options(nomem, nostack, preserves_flags));
     }
 
-    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    #[cfg(all(
+        not(any(target_arch = "x86_64", target_arch = "aarch64")),
+        target_os = "none"
+    ))]
     {
         loop {}
     }
+
+    // Hosted builds (the kernel's unit tests) can't execute privileged halts; there is
+    // no interrupt to wait for, so just return.
+    #[cfg(not(target_os = "none"))]
+    {}
+}
+
+/// Run `f` with interrupts masked on this CPU, then restore the previous mask.
+#[inline(always)]
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    #[cfg(all(target_arch = "aarch64", target_os = "none"))]
+    {
+        let daif: u64;
+        // No `nomem`: these double as compiler barriers around `f`.
+        unsafe {
+            core::arch::asm!("mrs {}, daif", "msr daifset, #2", out(reg) daif, options(nostack));
+        }
+        let r = f();
+        unsafe {
+            core::arch::asm!("msr daif, {}", in(reg) daif, options(nostack));
+        }
+        r
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "none"))]
+    {
+        let flags: u64;
+        unsafe {
+            core::arch::asm!("pushfq", "pop {}", "cli", out(reg) flags);
+        }
+        let r = f();
+        // Only re-enable if IF was set on entry.
+        if flags & (1 << 9) != 0 {
+            unsafe {
+                core::arch::asm!("sti", options(nostack));
+            }
+        }
+        r
+    }
+
+    // Other targets (and hosted builds) have no interrupts of ours to mask.
+    #[cfg(not(all(
+        any(target_arch = "x86_64", target_arch = "aarch64"),
+        target_os = "none"
+    )))]
+    {
+        f()
+    }
 }
 
 
@@ -0,0 +1,10 @@
+/// Interrupt controller operations the kernel needs to hand interrupts to drivers.
+///
+/// `irq` is the controller's own interrupt ID (a GIC INTID on aarch64).
+pub trait IrqController {
+    /// Stop `irq` from being signalled until `unmask`.
+    fn mask(&self, irq: u32);
+    fn unmask(&self, irq: u32);
+}
+
+
@@ -1,6 +1,9 @@
 #![no_std]
 
 pub mod arch;
+pub mod irq;
 pub mod log;
+pub mod mem;
+pub mod thread;
 
 
@@ -2,4 +2,14 @@ pub trait Logger {
     fn log(&self, s: &str);
 }
 
+/// Adapts a `Logger` to `core::fmt::Write`, so `write!` can format straight to it.
+pub struct LogWriter<'a>(pub &'a dyn Logger);
+
+impl core::fmt::Write for LogWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.0.log(s);
+        Ok(())
+    }
+}
+
 
@@ -0,0 +1,22 @@
+/// Maps physical frames handed over in an IPC page grant into a task's address space.
+///
+/// `space` identifies the receiving task's address space (the kernel passes its task
+/// index). Returns the virtual address the first frame is mapped at, or `None` if the
+/// frames could not be mapped.
+pub trait GrantMapper {
+    fn map_grant(&self, space: usize, phys: u64, frames: usize) -> Option<u64>;
+}
+
+/// Hands out physical frames for the kernel's own use, such as thread stacks.
+///
+/// The kernel accesses the frames at their physical address, so they must be identity
+/// mapped (or the MMU off).
+pub trait FrameAllocator: Sync {
+    /// Allocate `count` physically contiguous frames and return the first one's address.
+    fn alloc_frames(&self, count: usize) -> Option<u64>;
+
+    /// Return frames from an earlier `alloc_frames(count)`.
+    fn free_frames(&self, phys: u64, count: usize);
+}
+
+
@@ -0,0 +1,22 @@
+/// Where a new thread starts. It gets the `arg` it was created with and never returns.
+pub type ThreadEntry = extern "C" fn(usize) -> !;
+
+/// What the kernel's thread scheduler needs from the arch, besides the register save and
+/// restore its interrupt entry code does around every call into the kernel.
+///
+/// Contexts are opaque to the kernel: a `usize` handle, typically the address of the
+/// saved register block.
+pub trait ThreadArch: Sync {
+    /// Build the initial context of a thread that runs `entry(arg)` on `stack` and return
+    /// its handle. The context may live in the stack itself.
+    fn init(&self, stack: &'static mut [u8], entry: ThreadEntry, arg: usize) -> usize;
+
+    /// Restore `ctx` and run it. Used once, to start the first thread.
+    fn start(&self, ctx: usize) -> !;
+
+    /// Trap into the kernel from a running thread so it can pick another one, as a timer
+    /// tick would (see `kernel::thread_yield`). Returns once the thread runs again.
+    fn yield_now(&self);
+}
+
+
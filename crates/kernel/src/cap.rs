@@ -0,0 +1,156 @@
+//! Capabilities: what each task may do with which kernel object.
+//!
+//! A `Capability` names an `Object` and the `Rights` held on it: `SEND`, `RECV` and
+//! `GRANT`, whose meaning depends on the kind of object (see `Rights`). The router checks
+//! the current task's rights before every operation; the kernel itself holds every right.
+//!
+//! Each capability also carries a `Badge`, which the kernel stamps on messages sent
+//! through it so receivers can tell senders apart. Only a holder of every right on an
+//! object may pick a new badge when passing a capability on.
+//!
+//! A task's `CapTable` holds at most one capability per object. Granting a task a second
+//! capability to an object it already holds adds the new rights to the existing entry
+//! and keeps the existing badge; rights are never taken away except by destroying the
+//! object, which revokes it from every task.
+
+use core::ops::BitOr;
+
+use crate::ipc::{EndpointId, NotificationId, TopicId};
+
+// Size of each task's capability table.
+pub const MAX_CAPS: usize = 8;
+
+/// Set of operations a capability allows on its object.
+///
+/// For notifications, `SEND` allows `signal` and `RECV` allows `wait`/`poll`. For topics,
+/// `SEND` allows `publish` and `RECV` allows `subscribe`. For interrupt lines, `RECV`
+/// allows `bind_irq` and `unbind_irq`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Rights(u8);
+
+impl Rights {
+    pub const NONE: Rights = Rights(0);
+    /// Send (or `call`) to the endpoint.
+    pub const SEND: Rights = Rights(1 << 0);
+    /// Receive from the endpoint's mailbox.
+    pub const RECV: Rights = Rights(1 << 1);
+    /// Attach capabilities to messages sent to the endpoint.
+    pub const GRANT: Rights = Rights(1 << 2);
+    pub const ALL: Rights = Rights(0b111);
+
+    pub const fn contains(self, other: Rights) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Rights {
+    type Output = Rights;
+
+    fn bitor(self, rhs: Rights) -> Rights {
+        Rights(self.0 | rhs.0)
+    }
+}
+
+/// Kernel object a capability refers to.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Object {
+    Endpoint(EndpointId),
+    Notification(NotificationId),
+    Topic(TopicId),
+    /// Interrupt line, by INTID. Only the kernel hands these out, to the task driving the
+    /// device.
+    Irq(u32),
+}
+
+impl From<EndpointId> for Object {
+    fn from(id: EndpointId) -> Self {
+        Object::Endpoint(id)
+    }
+}
+
+impl From<NotificationId> for Object {
+    fn from(id: NotificationId) -> Self {
+        Object::Notification(id)
+    }
+}
+
+impl From<TopicId> for Object {
+    fn from(id: TopicId) -> Self {
+        Object::Topic(id)
+    }
+}
+
+/// Label the kernel stamps on every message sent through a capability.
+///
+/// Badges are chosen by whoever mints the capability (the kernel at boot, or a task
+/// holding every right on the object), so a receiver can trust them to tell its clients
+/// apart, unlike `MsgHeader::src`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Badge(pub u32);
+
+impl Badge {
+    pub const NONE: Badge = Badge(0);
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Capability {
+    pub obj: Object,
+    pub rights: Rights,
+    pub badge: Badge,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum CapError {
+    TableFull,
+    PermissionDenied,
+}
+
+/// A task's capabilities, at most one per object.
+///
+/// Holding two caps to the same object would only ever mean "the union of both", so
+/// `insert` merges rights instead of spending another slot. The first cap's badge wins.
+#[derive(Copy, Clone)]
+pub struct CapTable {
+    slots: [Option<Capability>; MAX_CAPS],
+}
+
+impl CapTable {
+    pub const fn new() -> Self {
+        Self {
+            slots: [None; MAX_CAPS],
+        }
+    }
+
+    pub fn get(&self, obj: Object) -> Option<Capability> {
+        self.slots.iter().flatten().find(|c| c.obj == obj).copied()
+    }
+
+    pub fn rights(&self, obj: Object) -> Rights {
+        self.get(obj).map_or(Rights::NONE, |c| c.rights)
+    }
+
+    pub fn insert(&mut self, cap: Capability) -> Result<(), CapError> {
+        if let Some(c) = self.slots.iter_mut().flatten().find(|c| c.obj == cap.obj) {
+            c.rights = c.rights | cap.rights;
+            return Ok(());
+        }
+        let slot = self
+            .slots
+            .iter_mut()
+            .find(|s| s.is_none())
+            .ok_or(CapError::TableFull)?;
+        *slot = Some(cap);
+        Ok(())
+    }
+
+    /// Drop the capability to `obj`, if any, freeing its slot.
+    pub fn remove(&mut self, obj: Object) {
+        for slot in self.slots.iter_mut() {
+            if slot.is_some_and(|c| c.obj == obj) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+
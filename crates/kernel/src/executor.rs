@@ -0,0 +1,200 @@
+//! Tasks written as `async fn`s, run by the cooperative scheduler.
+//!
+//! `AsyncTask` is a `sched::Task` that polls a future each time the scheduler polls the
+//! task, so async and hand-written tasks share the same loop. The future talks to the
+//! router through an `Io` handle, which is only usable while its task is being polled.
+//!
+//! Awaiting `Io::recv`, `Io::call_timeout` or `Io::sleep_until` parks the task in the
+//! router like the blocking calls do, with the waker from the future's `Context`. The
+//! router wakes it once a message arrives, the reply comes in or the deadline passes,
+//! and the scheduler polls the task again (see `ipc::waker`).
+
+use core::cell::Cell;
+use core::future::{poll_fn, Future};
+use core::pin::Pin;
+use core::ptr::NonNull;
+use core::task::{Context, Poll};
+
+use hal::log::Logger;
+
+use crate::ipc::{self, EndpointId, Message, RecvError, ReplyCap, ReplyError, SendError};
+use crate::sched::Task;
+
+/// An async task's access to IPC: its own endpoint, and the router while it is polled.
+pub struct Io {
+    ep: EndpointId,
+    // Set by `AsyncTask::poll` for the duration of the poll.
+    router: Cell<Option<NonNull<ipc::Router>>>,
+}
+
+impl Io {
+    pub const fn new(ep: EndpointId) -> Self {
+        Self {
+            ep,
+            router: Cell::new(None),
+        }
+    }
+
+    pub fn id(&self) -> EndpointId {
+        self.ep
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut ipc::Router) -> R) -> R {
+        let mut router = self
+            .router
+            .get()
+            .expect("executor: Io used outside of its task");
+        // SAFETY: the pointer is only set while `AsyncTask::poll` holds the router
+        // exclusively, and nothing else touches it until the future returns.
+        f(unsafe { router.as_mut() })
+    }
+
+    /// Current tick, the time base for deadlines.
+    pub fn now(&self) -> u64 {
+        self.with(|r| r.now())
+    }
+
+    /// Wait for a message on `ep`.
+    pub async fn recv(&self, ep: EndpointId) -> Result<Message, RecvError> {
+        poll_fn(|cx| {
+            self.with(|r| match r.recv_blocking(ep) {
+                Ok(Some(msg)) => Poll::Ready(Ok(msg)),
+                Ok(None) => {
+                    r.set_waker(cx.waker());
+                    Poll::Pending
+                }
+                Err(e) => Poll::Ready(Err(e)),
+            })
+        })
+        .await
+    }
+
+    /// Answer a call (see `Router::reply`).
+    pub fn reply(&self, cap: ReplyCap, msg: Message) -> Result<(), ReplyError> {
+        self.with(|r| r.reply(cap, msg))
+    }
+
+    /// Send `msg` as a call (see `Router::call_timeout`). The returned future resolves
+    /// to the reply, or `Timeout` once `deadline` passes without one.
+    pub fn call_timeout(&self, msg: Message, deadline: u64) -> Result<Reply<'_>, SendError> {
+        self.with(|r| r.call_timeout(msg, deadline))?;
+        Ok(Reply { io: self })
+    }
+
+    /// Wait until tick `deadline`.
+    pub async fn sleep_until(&self, deadline: u64) {
+        poll_fn(|cx| {
+            self.with(|r| {
+                if r.sleep_until(deadline) {
+                    return Poll::Ready(());
+                }
+                r.set_waker(cx.waker());
+                Poll::Pending
+            })
+        })
+        .await
+    }
+
+    /// Wait for `ticks` ticks.
+    pub async fn sleep(&self, ticks: u64) {
+        let deadline = self.now().saturating_add(ticks);
+        self.sleep_until(deadline).await
+    }
+
+    /// Print the recent IPC history (see `ipc::trace`).
+    pub fn dump_trace(&self, logger: &dyn Logger) {
+        self.with(|r| r.dump_trace(logger))
+    }
+}
+
+/// The answer to a call made with `Io::call_timeout`.
+pub struct Reply<'a> {
+    io: &'a Io,
+}
+
+impl Future for Reply<'_> {
+    type Output = Result<Message, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.io.with(|r| match r.take_reply() {
+            Ok(Some(msg)) => Poll::Ready(Ok(msg)),
+            Ok(None) => {
+                r.set_waker(cx.waker());
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(e)),
+        })
+    }
+}
+
+/// Runs a future as a scheduler task. Once the future completes, polls do nothing.
+pub struct AsyncTask<'a, F: Future<Output = ()>> {
+    io: &'a Io,
+    fut: Pin<&'a mut F>,
+    done: bool,
+}
+
+impl<'a, F: Future<Output = ()>> AsyncTask<'a, F> {
+    /// `fut` should be driven by `io` (e.g. `pin!(ping(&io, ...))`).
+    pub fn new(io: &'a Io, fut: Pin<&'a mut F>) -> Self {
+        Self {
+            io,
+            fut,
+            done: false,
+        }
+    }
+}
+
+impl<F: Future<Output = ()>> Task for AsyncTask<'_, F> {
+    fn id(&self) -> EndpointId {
+        self.io.ep
+    }
+
+    fn poll(&mut self, _logger: &dyn Logger, ipc: &mut ipc::Router, _tick: u64) {
+        if self.done {
+            return;
+        }
+        let waker = ipc.current_waker();
+        let mut cx = Context::from_waker(&waker);
+        self.io.router.set(Some(NonNull::from(ipc)));
+        self.done = self.fut.as_mut().poll(&mut cx).is_ready();
+        self.io.router.set(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::pin::pin;
+
+    use super::*;
+    use crate::cap::Rights;
+    use crate::sched::{run_for, TaskId};
+    use crate::testutil::{grant, msg, MockLogger};
+
+    #[test]
+    fn futures_park_until_ipc_or_deadline() {
+        let mut router = ipc::Router::new();
+        let ep = router.create_endpoint(1).unwrap();
+        grant(&mut router, 0, ep, Rights::RECV | Rights::SEND);
+
+        let got = Cell::new(None);
+        let io = Io::new(ep);
+        let fut = pin!(async {
+            io.sleep(3).await;
+            got.set(Some(io.recv(ep).await.map(|m| m.header.seq)));
+            io.sleep_until(u64::MAX).await;
+        });
+        let mut task = AsyncTask::new(&io, fut);
+        let mut tasks: [&mut dyn Task; 1] = [&mut task];
+        let log = MockLogger::default();
+        run_for(&mut tasks, &log, &mut router, 5);
+        assert_eq!(got.get(), None);
+        assert!(!router.is_runnable(TaskId::new(0)));
+
+        router.send(msg(ep, ep, 1, 42)).unwrap();
+        run_for(&mut tasks, &log, &mut router, 1);
+        assert_eq!(got.get(), Some(Ok(42)));
+    }
+}
+
+
@@ -1,114 +1,1429 @@
+//! Inter-task communication: the `Router` and the kernel objects it owns.
+//!
+//! The scheduler holds the router as `&mut` while it runs, so interrupt and exception
+//! handlers must never touch it. What they have to record goes into handler-side tables
+//! instead: `IrqLines` for raised interrupts, `FaultSlots` for faults and `WakeFlags` for
+//! woken wakers. Each is lock-free, lives in a `static` the router is given at boot, and
+//! is drained by the router the next time the scheduler runs it.
+
 #![allow(dead_code)]
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
-#[repr(u8)]
-pub enum EndpointId {
-    Ping = 1,
-    Pong = 2,
+use core::task::Waker;
+
+use hal::irq::IrqController;
+use hal::mem::{FrameAllocator, GrantMapper};
+
+use crate::cap::{Badge, CapError, CapTable, Capability, Object, Rights};
+use crate::sched::{TaskId, MAX_TASKS};
+
+mod fault;
+mod grant;
+mod irq;
+mod pipe;
+mod trace;
+mod waker;
+mod wire;
+
+pub use fault::{Fault, FaultAccess, FaultInfo, FaultReply, FaultSlots, FaultStatus};
+use grant::{FrameRun, MAX_FRAME_RUNS};
+use irq::IrqBinding;
+pub use irq::{IrqLines, MAX_IRQS};
+use pipe::{Pipe, MAX_PIPES};
+use trace::{Trace, TraceOp};
+pub use waker::WakeFlags;
+
+/// Defines a handle to an object in one of the router's tables.
+///
+/// The low byte is the table slot, the high byte is that slot's generation. Destroying an
+/// object bumps its slot's generation (see `Slot`), so a stale ID never aliases an object
+/// created later in the same slot.
+macro_rules! slot_id {
+    ($(#[$attr:meta])* $name:ident) => {
+        $(#[$attr])*
+        #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+        #[repr(transparent)]
+        pub struct $name(u16);
+
+        impl $name {
+            const fn new(slot: usize, generation: u8) -> Self {
+                Self(((generation as u16) << 8) | (slot as u16))
+            }
+
+            const fn slot(self) -> usize {
+                (self.0 & 0xFF) as usize
+            }
+
+            const fn generation(self) -> u8 {
+                (self.0 >> 8) as u8
+            }
+
+            pub const fn raw(self) -> u16 {
+                self.0
+            }
+
+            pub const fn from_raw(raw: u16) -> Self {
+                Self(raw)
+            }
+        }
+    };
 }
 
-#[derive(Copy, Clone, Debug)]
-#[repr(u8)]
-pub enum MsgType {
-    Ping = 1,
-    Pong = 2,
+// Size of the router's endpoint table. Endpoints are created and destroyed at runtime,
+// but the table itself is fixed so the router can live in a `static`.
+pub const MAX_ENDPOINTS: usize = 16;
+
+slot_id! {
+    /// Handle to an endpoint in the router's table.
+    EndpointId
 }
 
+// Size of the router's notification table.
+pub const MAX_NOTIFICATIONS: usize = 8;
+
+slot_id! {
+    /// Handle to a notification object.
+    NotificationId
+}
+
+// Size of the router's topic table, and how many endpoints may subscribe to one topic.
+pub const MAX_TOPICS: usize = 8;
+pub const MAX_SUBSCRIBERS: usize = 8;
+
+slot_id! {
+    /// Handle to a pub/sub topic.
+    TopicId
+}
+
+/// What `publish` does about a subscriber whose mailbox is full.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OverflowPolicy {
+    /// Discard that subscriber's oldest queued message to make room.
+    DropOldest,
+    /// Skip that subscriber; it never sees this message.
+    DropNewest,
+    /// Deliver to nobody and park the publisher until every subscriber has room.
+    Block,
+}
+
+/// Stable identifier of a message type, stored in `MsgHeader::ty`.
+///
+/// Assigned per type by `ipc_message!` (see `crate::message`).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(transparent)]
+pub struct MsgTag(pub u16);
+
+// Priority of ordinary traffic. Control messages (shutdown, cancel) should use something
+// higher so they overtake whatever is already queued.
+pub const PRIO_NORMAL: u8 = 0;
+
 // Keep these small during early bring-up so IPC queues fit comfortably on the stack
 // across all targets (we'll grow them once we have robust MMU + fault handling).
-pub const MAX_PAYLOAD: usize = 8;
+// A `Fault` (task, address, access, class) is the largest message the kernel sends.
+pub const MAX_PAYLOAD: usize = 16;
+
+// Upper bound on a mailbox's depth. Each endpoint picks its own depth (1..=MAX_MAILBOX_DEPTH)
+// when it is created; storage for the maximum is reserved per slot since we have no heap.
+pub const MAX_MAILBOX_DEPTH: usize = 8;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+// Largest number of frames a single page grant may carry.
+pub const MAX_GRANT_FRAMES: usize = 16;
+
+/// Physically contiguous frames lent to the receiver of a message.
+///
+/// This is how bulk data moves: the sender fills the frames and names them here, and the
+/// kernel maps them into the receiver's address space on receive, so nothing is copied and
+/// `MsgHeader` stays small. A task can only grant frames it got from `Router::alloc_grant`
+/// (see `grant`).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PageGrant {
+    /// Physical address of the first frame; must be page aligned.
+    pub phys: u64,
+    pub frames: usize,
+    /// Where the frames appear for the receiver. Filled in by the kernel on receive and
+    /// ignored on send.
+    pub va: u64,
+}
+
+impl PageGrant {
+    pub const fn new(phys: u64, frames: usize) -> Self {
+        Self {
+            phys,
+            frames,
+            va: 0,
+        }
+    }
+
+    pub const fn bytes(&self) -> usize {
+        self.frames * PAGE_SIZE as usize
+    }
 
+    fn is_valid(&self) -> bool {
+        self.phys & (PAGE_SIZE - 1) == 0 && self.frames > 0 && self.frames <= MAX_GRANT_FRAMES
+    }
+}
+
+/// In-kernel message header. Its layout is not an ABI; messages that leave the kernel
+/// use the versioned form in `wire`.
 #[derive(Copy, Clone, Debug)]
-#[repr(C)]
 pub struct MsgHeader {
     pub src: EndpointId,
     pub dst: EndpointId,
-    pub ty: MsgType,
+    pub ty: MsgTag,
     pub len: u8,
+    /// Delivery priority; higher is received first. Defaults to `PRIO_NORMAL`.
+    pub prio: u8,
     pub seq: u32,
 }
 
+/// One-shot capability to answer a `call`.
+///
+/// The kernel mints one per call and attaches it to the request; it names the blocked
+/// caller plus that call's nonce, so it stops working as soon as it has been used once
+/// (or the caller has moved on to another call).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ReplyCap {
+    task: TaskId,
+    nonce: u32,
+    // Endpoint the call was made to; the reply is badged with the replier's cap on it.
+    ep: EndpointId,
+}
+
+/// A message as the router queues it. Like `MsgHeader`, only `Message::to_wire` gives a
+/// stable byte layout.
 #[derive(Copy, Clone, Debug)]
-#[repr(C)]
 pub struct Message {
     pub header: MsgHeader,
     pub payload: [u8; MAX_PAYLOAD],
+    /// Set by the kernel on messages sent with `call`; always `None` on plain sends.
+    pub reply: Option<ReplyCap>,
+    /// Capability to hand to the receiver. Sending one needs `Rights::GRANT` on the
+    /// destination; it is installed in the receiver's cap table when the message is received.
+    pub cap: Option<Capability>,
+    /// Frames to lend to the receiver. Like `cap`, this needs `Rights::GRANT` on the
+    /// destination.
+    pub grant: Option<PageGrant>,
+    /// Badge of the capability the message was sent through, stamped by the kernel;
+    /// anything the sender puts here is overwritten. `Badge::NONE` from the kernel itself.
+    pub badge: Badge,
 }
 
-#[derive(Copy, Clone, Debug)]
+impl Message {
+    pub const fn new(header: MsgHeader, payload: [u8; MAX_PAYLOAD]) -> Self {
+        Self {
+            header,
+            payload,
+            reply: None,
+            cap: None,
+            grant: None,
+            badge: Badge::NONE,
+        }
+    }
+
+    pub const fn with_prio(mut self, prio: u8) -> Self {
+        self.header.prio = prio;
+        self
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum SendError {
+    /// The destination's mailbox (or, for `publish`, a subscriber's) has no room.
     MailboxFull,
+    /// The ID never named an endpoint (or topic).
+    NoSuchEndpoint,
+    /// The endpoint existed but has since been destroyed.
+    Closed,
+    /// The endpoint belongs to a pipe, which has no mailbox; write with `PipeWriter`.
+    IsPipe,
+    /// The sender lacks the right this operation needs on the destination.
+    PermissionDenied,
+    /// `header.len` is larger than `MAX_PAYLOAD`.
+    PayloadTooLarge,
+    /// The page grant is misaligned, empty or larger than `MAX_GRANT_FRAMES`.
+    InvalidGrant,
+    /// No `GrantMapper` is registered on this platform, so grants can't be delivered.
+    GrantsUnsupported,
+    /// The granted frames weren't allocated to the sender (see `Router::alloc_grant`).
+    ForeignGrant,
+    /// The deadline passed before the mailbox had room.
+    Timeout,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RecvError {
+    /// Nothing is queued (non-blocking `recv` only).
+    Empty,
+    NoSuchEndpoint,
+    /// The endpoint existed but has since been destroyed.
+    Closed,
+    /// The endpoint belongs to a pipe, which has no mailbox; read with `PipeReader`.
+    IsPipe,
+    PermissionDenied,
+    /// The deadline passed before a message (or reply) arrived.
+    Timeout,
+    /// The caller faulted while waiting for the reply, and the call was given up.
+    Aborted,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ReplyError {
+    /// The cap was already used, or its caller is no longer waiting on it.
+    StaleReplyCap,
+    /// `header.len` is larger than `MAX_PAYLOAD`.
+    PayloadTooLarge,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EndpointError {
+    TableFull,
+    NoSuchEndpoint,
+    InvalidDepth,
+    /// The creating task's cap table has no room for the new endpoint's capability.
+    CapTableFull,
+    PermissionDenied,
+    /// The topic already has `MAX_SUBSCRIBERS` subscribers.
+    SubscribersFull,
+    /// The endpoint belongs to a pipe, which has no mailbox to deliver into.
+    IsPipe,
+}
+
+/// Why an `EndpointId` didn't resolve; converted into each operation's own error type.
+#[derive(Copy, Clone)]
+enum Missing {
+    NoSuchEndpoint,
+    Closed,
+    /// The endpoint exists, but as a pipe's, so it has no mailbox (see `lookup_mailbox`).
+    Pipe,
+}
+
+impl From<Missing> for SendError {
+    fn from(m: Missing) -> Self {
+        match m {
+            Missing::NoSuchEndpoint => SendError::NoSuchEndpoint,
+            Missing::Closed => SendError::Closed,
+            Missing::Pipe => SendError::IsPipe,
+        }
+    }
+}
+
+impl From<Missing> for RecvError {
+    fn from(m: Missing) -> Self {
+        match m {
+            Missing::NoSuchEndpoint => RecvError::NoSuchEndpoint,
+            Missing::Closed => RecvError::Closed,
+            Missing::Pipe => RecvError::IsPipe,
+        }
+    }
+}
+
+impl From<Missing> for EndpointError {
+    fn from(m: Missing) -> Self {
+        match m {
+            Missing::NoSuchEndpoint | Missing::Closed => EndpointError::NoSuchEndpoint,
+            Missing::Pipe => EndpointError::IsPipe,
+        }
+    }
 }
 
+/// Fixed-capacity ring of messages, received in priority order.
+///
+/// `head` is the index of the oldest message and `len` the number queued; the ring
+/// only uses the first `depth` entries of `buf`. Messages stay in arrival order and
+/// `take` picks the oldest one of the highest priority, so equal priorities are FIFO.
 #[derive(Copy, Clone)]
 struct Mailbox {
-    full: bool,
-    msg: Message,
+    buf: [Message; MAX_MAILBOX_DEPTH],
+    head: usize,
+    len: usize,
+    depth: usize,
 }
 
 impl Mailbox {
-    const EMPTY: Message = Message {
-        header: MsgHeader {
-            src: EndpointId::Ping,
-            dst: EndpointId::Ping,
-            ty: MsgType::Ping,
+    const EMPTY: Message = Message::new(
+        MsgHeader {
+            src: EndpointId(0),
+            dst: EndpointId(0),
+            ty: MsgTag(0),
             len: 0,
+            prio: PRIO_NORMAL,
             seq: 0,
         },
-        payload: [0; MAX_PAYLOAD],
-    };
+        [0; MAX_PAYLOAD],
+    );
 
-    const fn new() -> Self {
+    const fn new(depth: usize) -> Self {
         Self {
-            full: false,
-            msg: Self::EMPTY,
+            buf: [Self::EMPTY; MAX_MAILBOX_DEPTH],
+            head: 0,
+            len: 0,
+            depth,
         }
     }
 
+    fn free(&self) -> usize {
+        self.depth - self.len
+    }
+
     fn put(&mut self, msg: Message) -> Result<(), SendError> {
-        if self.full {
+        if self.len == self.depth {
             return Err(SendError::MailboxFull);
         }
-        self.msg = msg;
-        self.full = true;
+        let tail = (self.head + self.len) % self.depth;
+        self.buf[tail] = msg;
+        self.len += 1;
         Ok(())
     }
 
     fn take(&mut self) -> Option<Message> {
-        if !self.full {
-            return None;
+        // `max_by_key` returns the last maximum, so scan newest-first to get the oldest.
+        let pos = (0..self.len)
+            .rev()
+            .max_by_key(|&i| self.buf[(self.head + i) % self.depth].header.prio)?;
+        Some(self.remove(pos))
+    }
+
+    /// Remove the oldest message regardless of priority.
+    fn drop_oldest(&mut self) -> Option<Message> {
+        (self.len > 0).then(|| self.remove(0))
+    }
+
+    // Remove the `pos`th queued message (0 = oldest), closing the gap by shifting the
+    // newer ones down. Depths are tiny, so this is cheaper than keeping per-priority lists.
+    fn remove(&mut self, pos: usize) -> Message {
+        let at = |i: usize| (self.head + i) % self.depth;
+        let msg = self.buf[at(pos)];
+        for i in pos..self.len - 1 {
+            self.buf[at(i)] = self.buf[at(i + 1)];
+        }
+        self.len -= 1;
+        msg
+    }
+}
+
+/// Whether a table slot holds an object, and which generation of IDs names it.
+#[derive(Copy, Clone)]
+struct Slot {
+    live: bool,
+    // Whether the slot has ever held an object; IDs can be forged with `from_raw`, and
+    // one naming a slot that was never used shouldn't look like a destroyed object.
+    used: bool,
+    generation: u8,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            live: false,
+            used: false,
+            generation: 0,
+        }
+    }
+
+    fn check(&self, generation: u8) -> Result<(), Missing> {
+        if !self.used {
+            return Err(Missing::NoSuchEndpoint);
+        }
+        // IDs are only handed out on creation, so a used slot that doesn't match was live
+        // once and has been destroyed since.
+        if self.live && self.generation == generation {
+            Ok(())
+        } else {
+            Err(Missing::Closed)
+        }
+    }
+
+    fn claim(&mut self) {
+        self.live = true;
+        self.used = true;
+    }
+
+    fn retire(&mut self) {
+        self.live = false;
+        self.generation = self.generation.wrapping_add(1);
+    }
+}
+
+/// An entry in one of the router's object tables.
+trait Entry {
+    fn slot(&self) -> &Slot;
+}
+
+/// The live entry `slot`/`generation` (an ID's two halves) name in `table`.
+fn lookup_in<T: Entry>(table: &mut [T], slot: usize, generation: u8) -> Result<&mut T, Missing> {
+    let entry = table.get_mut(slot).ok_or(Missing::NoSuchEndpoint)?;
+    entry.slot().check(generation)?;
+    Ok(entry)
+}
+
+/// Index of the first unused slot in `table`.
+fn free_slot<T: Entry>(table: &[T]) -> Option<usize> {
+    table.iter().position(|e| !e.slot().live)
+}
+
+#[derive(Copy, Clone)]
+struct Endpoint {
+    slot: Slot,
+    mailbox: Mailbox,
+    // Set for a pipe's endpoint, whose data lives in the pipe instead of the mailbox.
+    pipe: bool,
+    // Bitmask (by task index) of tasks parked in `recv_blocking` on this endpoint.
+    waiters: u32,
+    // Bitmask (by task index) of publishers parked until this mailbox has room.
+    senders: u32,
+}
+
+impl Endpoint {
+    const fn new() -> Self {
+        Self {
+            slot: Slot::new(),
+            mailbox: Mailbox::new(0),
+            pipe: false,
+            waiters: 0,
+            senders: 0,
+        }
+    }
+}
+
+/// A word of pending signal bits, for events that need a wakeup but no message body
+/// (timer expiry, IRQs, "data ready").
+#[derive(Copy, Clone)]
+struct Notification {
+    slot: Slot,
+    pending: usize,
+    // Bitmask (by task index) of tasks parked in `wait` on this notification.
+    waiters: u32,
+}
+
+impl Notification {
+    const fn new() -> Self {
+        Self {
+            slot: Slot::new(),
+            pending: 0,
+            waiters: 0,
+        }
+    }
+}
+
+/// A named fan-out point: each publish is copied into every subscribed endpoint's mailbox.
+#[derive(Copy, Clone)]
+struct Topic {
+    slot: Slot,
+    policy: OverflowPolicy,
+    subscribers: [Option<EndpointId>; MAX_SUBSCRIBERS],
+}
+
+impl Topic {
+    const fn new() -> Self {
+        Self {
+            slot: Slot::new(),
+            policy: OverflowPolicy::DropNewest,
+            subscribers: [None; MAX_SUBSCRIBERS],
+        }
+    }
+}
+
+impl Entry for Endpoint {
+    fn slot(&self) -> &Slot {
+        &self.slot
+    }
+}
+
+impl Entry for Notification {
+    fn slot(&self) -> &Slot {
+        &self.slot
+    }
+}
+
+impl Entry for Topic {
+    fn slot(&self) -> &Slot {
+        &self.slot
+    }
+}
+
+/// How long a receive or send may park the current task.
+#[derive(Copy, Clone)]
+enum Wait {
+    Never,
+    Forever,
+    Until(u64),
+}
+
+// Waiter and wakeup sets are `u32` bitmasks indexed by task.
+const _: () = assert!(MAX_TASKS <= 32);
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum TaskState {
+    Runnable,
+    /// Parked in `call` until the holder of the matching `ReplyCap` answers.
+    WaitingReply,
+    /// Parked in `recv_blocking` until something is sent to this endpoint.
+    WaitingRecv(EndpointId),
+    /// Parked in `wait` until this notification is signalled.
+    WaitingSignal(NotificationId),
+    /// Parked in `publish` until every subscriber of this topic has room.
+    WaitingSpace(TopicId),
+    /// Parked in `send_timeout` until this endpoint's mailbox has room.
+    WaitingSend(EndpointId),
+    /// Parked in `sleep_until` until its deadline.
+    Sleeping,
+    /// Took a fault; waits for its pager to reply, and nothing else wakes it.
+    Faulted,
+    /// Killed by its pager (or for lack of one). Never runs again.
+    Dead,
+}
+
+/// Per-task IPC bookkeeping the router keeps on behalf of the scheduler.
+#[derive(Copy, Clone)]
+struct TaskIpc {
+    state: TaskState,
+    /// Nonce of the task's most recent call or fault (see `fault`); a `ReplyCap` is only
+    /// valid while it matches.
+    reply_nonce: u32,
+    /// Header of that call, kept so a timeout can be traced against it.
+    call_header: MsgHeader,
+    reply: Option<Message>,
+    caps: CapTable,
+    /// Tick at which a parked task is woken even if nothing arrived.
+    deadline: Option<u64>,
+    /// Why the last call ended without a reply; reported once by `take_reply`.
+    call_error: Option<RecvError>,
+    /// Endpoint the task's faults are sent to (see `fault`).
+    pager: Option<EndpointId>,
+}
+
+impl TaskIpc {
+    const fn new() -> Self {
+        Self {
+            state: TaskState::Runnable,
+            reply_nonce: 0,
+            call_header: Mailbox::EMPTY.header,
+            reply: None,
+            caps: CapTable::new(),
+            deadline: None,
+            call_error: None,
+            pager: None,
         }
-        self.full = false;
-        Some(self.msg)
     }
 }
 
 pub struct Router {
-    ping: Mailbox,
-    pong: Mailbox,
+    endpoints: [Endpoint; MAX_ENDPOINTS],
+    notifications: [Notification; MAX_NOTIFICATIONS],
+    topics: [Topic; MAX_TOPICS],
+    tasks: [TaskIpc; MAX_TASKS],
+    // The task the scheduler is currently polling; blocking operations park this task and
+    // rights are checked against its cap table. `None` until the scheduler starts, while
+    // the kernel itself is setting up endpoints, and the kernel holds every right.
+    current: Option<TaskId>,
+    // Tasks made runnable since the scheduler last asked (see `take_woken`).
+    woken: u32,
+    // Arch hook used to map page grants into the receiver; `None` where there's no MMU
+    // support yet, in which case grants are refused at send time.
+    grant_mapper: Option<&'static dyn GrantMapper>,
+    // Where grantable frames come from, and which task each allocation belongs to.
+    frames: Option<&'static dyn FrameAllocator>,
+    frame_runs: [Option<FrameRun>; MAX_FRAME_RUNS],
+    // Platform tick counter that deadlines are measured against. Without one the router
+    // counts scheduler ticks itself (see `advance_tick`).
+    clock: Option<fn() -> u64>,
+    ticks: u64,
+    trace: Trace,
+    irqs: [Option<IrqBinding>; MAX_IRQS],
+    // Platform interrupt controller and the table its IRQ handler raises lines in; `None`
+    // until the arch registers them (see `set_irq_controller`).
+    irq_ctl: Option<&'static dyn IrqController>,
+    irq_lines: Option<&'static IrqLines>,
+    // Where the arch exception handler records task faults; `None` until registered.
+    fault_slots: Option<&'static FaultSlots>,
+    // Called once a pager has resumed a task (see `set_resume_hook`).
+    on_resume: Option<fn()>,
+    // Flags set by task wakers (see `waker`); `None` until registered.
+    wake_flags: Option<&'static WakeFlags>,
+    // Waker of the future each task is parked in, if any; woken in its place.
+    wakers: [Option<Waker>; MAX_TASKS],
+    pipes: [Pipe; MAX_PIPES],
 }
 
 impl Router {
     pub const fn new() -> Self {
         Self {
-            ping: Mailbox::new(),
-            pong: Mailbox::new(),
+            endpoints: [Endpoint::new(); MAX_ENDPOINTS],
+            notifications: [Notification::new(); MAX_NOTIFICATIONS],
+            topics: [Topic::new(); MAX_TOPICS],
+            tasks: [TaskIpc::new(); MAX_TASKS],
+            current: None,
+            woken: 0,
+            grant_mapper: None,
+            frames: None,
+            frame_runs: [None; MAX_FRAME_RUNS],
+            clock: None,
+            ticks: 0,
+            trace: Trace::new(),
+            irqs: [None; MAX_IRQS],
+            irq_ctl: None,
+            irq_lines: None,
+            fault_slots: None,
+            on_resume: None,
+            wake_flags: None,
+            wakers: [const { None }; MAX_TASKS],
+            pipes: [Pipe::new(); MAX_PIPES],
+        }
+    }
+
+    pub fn set_grant_mapper(&mut self, mapper: &'static dyn GrantMapper) {
+        self.grant_mapper = Some(mapper);
+    }
+
+    pub fn set_clock(&mut self, clock: fn() -> u64) {
+        self.clock = Some(clock);
+    }
+
+    /// Current tick, the time base for IPC deadlines.
+    pub fn now(&self) -> u64 {
+        self.clock.map_or(self.ticks, |clock| clock())
+    }
+
+    /// Called by the scheduler once per tick. Wakes every task whose deadline has passed
+    /// and returns the new current tick.
+    pub fn advance_tick(&mut self) -> u64 {
+        self.ticks = self.ticks.wrapping_add(1);
+        let now = self.now();
+        let mut expired = 0;
+        for (i, t) in self.tasks.iter_mut().enumerate() {
+            if t.deadline.is_some_and(|d| now >= d) {
+                if t.state == TaskState::WaitingReply {
+                    t.call_error = Some(RecvError::Timeout);
+                }
+                expired |= 1 << i;
+            }
+        }
+        self.wake(expired);
+        now
+    }
+
+    /// Called by the scheduler before it polls `task`.
+    pub fn set_current(&mut self, task: TaskId) {
+        self.current = Some(task);
+    }
+
+    /// Whether the scheduler should poll `task`, i.e. it is not parked in a blocking call.
+    pub fn is_runnable(&self, task: TaskId) -> bool {
+        self.tasks[task.index()].state == TaskState::Runnable
+    }
+
+    /// Drain the set of tasks woken since the last call, as a bitmask by task index.
+    /// Includes tasks whose wakers were woken.
+    pub fn take_woken(&mut self) -> u32 {
+        core::mem::take(&mut self.woken) | self.take_waker_wakes()
+    }
+
+    /// Give `task` a capability. Only the kernel may do this (i.e. before the scheduler
+    /// starts); tasks pass capabilities on by attaching them to messages.
+    pub fn install_cap(&mut self, task: TaskId, cap: Capability) -> Result<(), CapError> {
+        if self.current.is_some() {
+            return Err(CapError::PermissionDenied);
+        }
+        self.tasks[task.index()].caps.insert(cap)
+    }
+
+    /// Create an endpoint whose mailbox holds up to `depth` messages.
+    ///
+    /// A task that creates an endpoint, or any other kernel object, receives a capability
+    /// with every right on it.
+    pub fn create_endpoint(&mut self, depth: usize) -> Result<EndpointId, EndpointError> {
+        if depth == 0 || depth > MAX_MAILBOX_DEPTH {
+            return Err(EndpointError::InvalidDepth);
+        }
+        let slot = free_slot(&self.endpoints).ok_or(EndpointError::TableFull)?;
+        let id = EndpointId::new(slot, self.endpoints[slot].slot.generation);
+        self.grant_creator(id.into())?;
+        let ep = &mut self.endpoints[slot];
+        ep.slot.claim();
+        ep.mailbox = Mailbox::new(depth);
+        ep.pipe = false;
+        Ok(id)
+    }
+
+    /// Destroy an endpoint. Needs every right on it, which normally means its creator.
+    pub fn destroy_endpoint(&mut self, id: EndpointId) -> Result<(), EndpointError> {
+        if !self.rights(id.into()).contains(Rights::ALL) {
+            return Err(EndpointError::PermissionDenied);
+        }
+        Ok(self.retire_endpoint(id)?)
+    }
+
+    /// Destroy an endpoint without a rights check.
+    fn retire_endpoint(&mut self, id: EndpointId) -> Result<(), Missing> {
+        let ep = self.lookup(id)?;
+        ep.slot.retire();
+        // Drop anything still queued so it can't leak into the slot's next owner.
+        let mut queued = core::mem::replace(&mut ep.mailbox, Mailbox::new(0));
+        // Anyone parked here would otherwise sleep forever; let them see it's gone.
+        let waiters = core::mem::take(&mut ep.waiters) | core::mem::take(&mut ep.senders);
+        self.wake(waiters);
+        self.revoke(id.into());
+        // Likewise callers whose requests were dropped, as nobody can answer them now.
+        while let Some(msg) = queued.drop_oldest() {
+            if let Some(cap) = msg.reply {
+                self.abandon_call(cap);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail the call `cap` would have answered with `RecvError::Closed`. For a fault,
+    /// whose pager is gone, the task is killed as `forward_fault` would have.
+    fn abandon_call(&mut self, cap: ReplyCap) {
+        let t = &mut self.tasks[cap.task.index()];
+        if t.reply_nonce != cap.nonce {
+            return;
+        }
+        match t.state {
+            TaskState::WaitingReply => {
+                t.call_error = Some(RecvError::Closed);
+                self.wake(1 << cap.task.index());
+            }
+            TaskState::Faulted => self.kill(cap.task),
+            _ => {}
         }
     }
 
     pub fn send(&mut self, msg: Message) -> Result<(), SendError> {
-        match msg.header.dst {
-            EndpointId::Ping => self.ping.put(msg),
-            EndpointId::Pong => self.pong.put(msg),
+        self.send_with(msg, Wait::Never)
+    }
+
+    /// Take the next message from `dst`: the oldest one of the highest priority queued.
+    pub fn recv(&mut self, dst: EndpointId) -> Result<Message, RecvError> {
+        self.recv_with(dst, Wait::Never)?.ok_or(RecvError::Empty)
+    }
+
+    /// Like `recv`, but if the mailbox is empty the current task is parked until a
+    /// message is sent to `dst`. The scheduler skips it until then; when it runs again it
+    /// should retry the receive.
+    pub fn recv_blocking(&mut self, dst: EndpointId) -> Result<Option<Message>, RecvError> {
+        self.recv_with(dst, Wait::Forever)
+    }
+
+    /// Like `send`, but if the mailbox is full the current task is parked until a message
+    /// is taken from it or `deadline` (in ticks, see `now`) passes.
+    ///
+    /// Parking returns `MailboxFull`; once it runs again the task should retry with the
+    /// same deadline, which fails with `Timeout` if the mailbox is still full by then.
+    pub fn send_timeout(&mut self, msg: Message, deadline: u64) -> Result<(), SendError> {
+        self.send_with(msg, Wait::Until(deadline))
+    }
+
+    /// Like `recv_blocking`, but gives up with `Timeout` once `deadline` has passed and
+    /// the mailbox is still empty.
+    pub fn recv_timeout(
+        &mut self,
+        dst: EndpointId,
+        deadline: u64,
+    ) -> Result<Option<Message>, RecvError> {
+        self.recv_with(dst, Wait::Until(deadline))
+    }
+
+    /// Number of messages `id` can still accept before `send` reports `MailboxFull`.
+    pub fn free_slots(&mut self, id: EndpointId) -> Option<usize> {
+        if !self.rights(id.into()).contains(Rights::SEND) {
+            return None;
         }
+        Some(self.lookup_mailbox(id).ok()?.mailbox.free())
     }
 
-    pub fn recv(&mut self, dst: EndpointId) -> Option<Message> {
-        match dst {
-            EndpointId::Ping => self.ping.take(),
-            EndpointId::Pong => self.pong.take(),
+    /// Send `msg` to `msg.header.dst` and block the current task until it is answered.
+    ///
+    /// The request carries a fresh `ReplyCap` for the receiver. The scheduler won't poll
+    /// the caller again until the reply has been delivered; it then collects it with
+    /// `take_reply`.
+    pub fn call(&mut self, msg: Message) -> Result<(), SendError> {
+        self.call_until(msg, None)
+    }
+
+    /// Like `call`, but the caller is woken at `deadline` if no reply has arrived by then.
+    /// `take_reply` then reports `Timeout`, and a late reply is refused as stale.
+    pub fn call_timeout(&mut self, msg: Message, deadline: u64) -> Result<(), SendError> {
+        if self.now() >= deadline {
+            return Err(SendError::Timeout);
         }
+        self.call_until(msg, Some(deadline))
     }
+
+    /// Collect the reply to the current task's last `call`, if it has arrived.
+    pub fn take_reply(&mut self) -> Result<Option<Message>, RecvError> {
+        let t = &mut self.tasks[self.current_task().index()];
+        if let Some(e) = t.call_error.take() {
+            let header = t.call_header;
+            self.trace_op(TraceOp::RecvFailed(e), &header);
+            return Err(e);
+        }
+        Ok(t.reply.take())
+    }
+
+    /// Answer a call and wake its caller. Each `ReplyCap` works exactly once.
+    ///
+    /// The reply cap is all the authority needed, so no endpoint rights are checked.
+    /// Replies can't transfer capabilities or grants; any attached `cap`/`grant` is dropped.
+    /// The reply is badged with the replier's capability on the endpoint that was called.
+    ///
+    /// Replying to a `Fault` resumes or kills the faulted task instead (see `fault`).
+    pub fn reply(&mut self, cap: ReplyCap, mut msg: Message) -> Result<(), ReplyError> {
+        msg.badge = self.badge(cap.ep.into());
+        let t = &mut self.tasks[cap.task.index()];
+        let waiting = matches!(t.state, TaskState::WaitingReply | TaskState::Faulted);
+        let err = if !waiting || t.reply_nonce != cap.nonce {
+            Some(ReplyError::StaleReplyCap)
+        } else if check_len(&msg.header).is_err() {
+            Some(ReplyError::PayloadTooLarge)
+        } else {
+            None
+        };
+        if let Some(e) = err {
+            self.trace_op(TraceOp::ReplyFailed(e), &msg.header);
+            return Err(e);
+        }
+        if t.state == TaskState::Faulted {
+            self.trace_op(TraceOp::Reply, &msg.header);
+            self.resolve_fault(cap.task, msg.decode());
+            return Ok(());
+        }
+        msg.reply = None;
+        msg.cap = None;
+        msg.grant = None;
+        t.reply = Some(msg);
+        self.wake(1 << cap.task.index());
+        self.trace_op(TraceOp::Reply, &msg.header);
+        Ok(())
+    }
+
+    /// Answer a call, then wait for the next message on `ep` as `recv_blocking` does.
+    ///
+    /// This is the usual server loop step. As with seL4, a reply whose caller is no
+    /// longer waiting is dropped rather than failing the receive.
+    pub fn reply_recv(
+        &mut self,
+        ep: EndpointId,
+        cap: ReplyCap,
+        msg: Message,
+    ) -> Result<Option<Message>, RecvError> {
+        let _ = self.reply(cap, msg);
+        self.recv_blocking(ep)
+    }
+
+    pub fn create_notification(&mut self) -> Result<NotificationId, EndpointError> {
+        let slot = free_slot(&self.notifications).ok_or(EndpointError::TableFull)?;
+        let id = NotificationId::new(slot, self.notifications[slot].slot.generation);
+        self.grant_creator(id.into())?;
+        let n = &mut self.notifications[slot];
+        n.slot.claim();
+        n.pending = 0;
+        Ok(id)
+    }
+
+    pub fn destroy_notification(&mut self, id: NotificationId) -> Result<(), EndpointError> {
+        if !self.rights(id.into()).contains(Rights::ALL) {
+            return Err(EndpointError::PermissionDenied);
+        }
+        let n = self.lookup_notification(id)?;
+        n.slot.retire();
+        let waiters = core::mem::take(&mut n.waiters);
+        self.revoke(id.into());
+        self.wake(waiters);
+        Ok(())
+    }
+
+    /// OR `bits` into the notification's pending word and wake anyone waiting on it.
+    ///
+    /// Signalling never blocks and never fails, so it is safe to use for events that
+    /// must not be lost to backpressure. A signal to a destroyed notification, or from a
+    /// task without `Rights::SEND` on it, is ignored.
+    pub fn signal(&mut self, id: NotificationId, bits: usize) {
+        if self.rights(id.into()).contains(Rights::SEND) {
+            let _ = self.notify(id, bits);
+        }
+    }
+
+    /// Return and clear the pending bits without blocking; 0 if nothing is pending.
+    pub fn poll(&mut self, id: NotificationId) -> Result<usize, RecvError> {
+        if !self.rights(id.into()).contains(Rights::RECV) {
+            return Err(RecvError::PermissionDenied);
+        }
+        let n = self.lookup_notification(id)?;
+        Ok(core::mem::take(&mut n.pending))
+    }
+
+    /// Return and clear the pending bits. If none are pending, returns 0 and parks the
+    /// current task until the next `signal`, after which it should wait again.
+    pub fn wait(&mut self, id: NotificationId) -> Result<usize, RecvError> {
+        let bits = self.poll(id)?;
+        if bits != 0 {
+            return Ok(bits);
+        }
+        let task = self.current_task();
+        if let Ok(n) = self.lookup_notification(id) {
+            n.waiters |= 1 << task.index();
+        }
+        self.park(task, TaskState::WaitingSignal(id), None);
+        Ok(0)
+    }
+
+    /// Create a topic whose slow subscribers are handled according to `policy`.
+    pub fn create_topic(&mut self, policy: OverflowPolicy) -> Result<TopicId, EndpointError> {
+        let slot = free_slot(&self.topics).ok_or(EndpointError::TableFull)?;
+        let id = TopicId::new(slot, self.topics[slot].slot.generation);
+        self.grant_creator(id.into())?;
+        let t = &mut self.topics[slot];
+        t.slot.claim();
+        t.policy = policy;
+        t.subscribers = [None; MAX_SUBSCRIBERS];
+        Ok(id)
+    }
+
+    pub fn destroy_topic(&mut self, id: TopicId) -> Result<(), EndpointError> {
+        if !self.rights(id.into()).contains(Rights::ALL) {
+            return Err(EndpointError::PermissionDenied);
+        }
+        let t = self.lookup_topic(id)?;
+        t.slot.retire();
+        self.revoke(id.into());
+        // Publishers parked on a full subscriber would otherwise retry a dead topic forever.
+        let publishers = self.parked_on(TaskState::WaitingSpace(id));
+        self.wake(publishers);
+        Ok(())
+    }
+
+    /// Have every later publish to `topic` delivered into `ep`'s mailbox.
+    ///
+    /// Needs `Rights::RECV` on both: the topic's to listen to it, and the endpoint's since
+    /// only a task that reads a mailbox should decide what flows into it. Subscribing
+    /// twice is a no-op.
+    pub fn subscribe(&mut self, topic: TopicId, ep: EndpointId) -> Result<(), EndpointError> {
+        if !self.rights(topic.into()).contains(Rights::RECV)
+            || !self.rights(ep.into()).contains(Rights::RECV)
+        {
+            return Err(EndpointError::PermissionDenied);
+        }
+        self.lookup_mailbox(ep)?;
+        let t = self.lookup_topic(topic)?;
+        if t.subscribers.contains(&Some(ep)) {
+            return Ok(());
+        }
+        let slot = t
+            .subscribers
+            .iter_mut()
+            .find(|s| s.is_none())
+            .ok_or(EndpointError::SubscribersFull)?;
+        *slot = Some(ep);
+        Ok(())
+    }
+
+    pub fn unsubscribe(&mut self, topic: TopicId, ep: EndpointId) -> Result<(), EndpointError> {
+        if !self.rights(ep.into()).contains(Rights::RECV) {
+            return Err(EndpointError::PermissionDenied);
+        }
+        let t = self.lookup_topic(topic)?;
+        for s in t.subscribers.iter_mut().filter(|s| **s == Some(ep)) {
+            *s = None;
+        }
+        // A publisher may have been parked on this subscriber alone.
+        let publishers = self.parked_on(TaskState::WaitingSpace(topic));
+        self.wake(publishers);
+        Ok(())
+    }
+
+    /// Deliver a copy of `msg` to every subscriber of `topic`, with `header.dst` set to
+    /// the subscriber's endpoint. Returns how many subscribers got it.
+    ///
+    /// Needs `Rights::SEND` on the topic; no rights on the subscribers are needed. A
+    /// message can't be handed to several receivers at once with a reply cap, capability
+    /// or grant attached, so those are dropped. When a subscriber is full, the topic's
+    /// `OverflowPolicy` decides; under `Block`, nothing is delivered, the current task is
+    /// parked until the full subscribers drain, and `MailboxFull` is returned so the
+    /// publisher knows to publish again once it runs.
+    pub fn publish(&mut self, topic: TopicId, msg: Message) -> Result<usize, SendError> {
+        let r = self.try_publish(topic, msg);
+        if let Err(e) = r {
+            self.trace_op(TraceOp::SendFailed(e), &msg.header);
+        }
+        r
+    }
+
+    /// Dump the IPC trace ring through `logger`, oldest event first.
+    pub fn dump_trace(&self, logger: &dyn hal::log::Logger) {
+        self.trace.dump(logger);
+    }
+
+    fn try_publish(&mut self, topic: TopicId, mut msg: Message) -> Result<usize, SendError> {
+        if !self.rights(topic.into()).contains(Rights::SEND) {
+            return Err(SendError::PermissionDenied);
+        }
+        check_len(&msg.header)?;
+        msg.reply = None;
+        msg.cap = None;
+        msg.grant = None;
+        msg.badge = self.badge(topic.into());
+        let t = *self.lookup_topic(topic)?;
+
+        if t.policy == OverflowPolicy::Block {
+            let full = t
+                .subscribers
+                .iter()
+                .flatten()
+                .filter(|&&ep| self.lookup(ep).is_ok_and(|e| e.mailbox.free() == 0))
+                .fold(0u32, |mask, ep| mask | 1 << ep.slot());
+            if full != 0 {
+                let Some(task) = self.current else {
+                    // The kernel itself can't be parked.
+                    return Err(SendError::MailboxFull);
+                };
+                // Whichever of these drains first wakes the publisher, and `wake` then
+                // clears it from the others.
+                for (i, ep) in self.endpoints.iter_mut().enumerate() {
+                    if full & (1 << i) != 0 {
+                        ep.senders |= 1 << task.index();
+                    }
+                }
+                self.park(task, TaskState::WaitingSpace(topic), None);
+                return Err(SendError::MailboxFull);
+            }
+        }
+
+        let mut delivered = 0;
+        for (i, sub) in t.subscribers.iter().enumerate() {
+            let Some(dst) = *sub else {
+                continue;
+            };
+            let Ok(ep) = self.lookup(dst) else {
+                // The endpoint was destroyed; forget it.
+                self.topics[topic.slot()].subscribers[i] = None;
+                continue;
+            };
+            if ep.mailbox.free() == 0 {
+                match t.policy {
+                    OverflowPolicy::DropOldest => {
+                        ep.mailbox.drop_oldest();
+                    }
+                    OverflowPolicy::DropNewest | OverflowPolicy::Block => continue,
+                }
+            }
+            msg.header.dst = dst;
+            if ep.mailbox.put(msg).is_ok() {
+                delivered += 1;
+            }
+            let waiters = core::mem::take(&mut ep.waiters);
+            self.wake(waiters);
+            self.trace_op(TraceOp::Send, &msg.header);
+        }
+        Ok(delivered)
+    }
+
+    fn call_until(&mut self, mut msg: Message, deadline: Option<u64>) -> Result<(), SendError> {
+        let task = self.current_task();
+        let nonce = self.tasks[task.index()].reply_nonce.wrapping_add(1);
+        msg.reply = Some(ReplyCap {
+            task,
+            nonce,
+            ep: msg.header.dst,
+        });
+        let r = self.check_send(&msg).and_then(|()| self.enqueue(msg));
+        if let Err(e) = r {
+            self.trace_op(TraceOp::SendFailed(e), &msg.header);
+            return Err(e);
+        }
+
+        let t = &mut self.tasks[task.index()];
+        t.reply_nonce = nonce;
+        t.call_header = msg.header;
+        t.reply = None;
+        t.call_error = None;
+        self.park(task, TaskState::WaitingReply, deadline);
+        Ok(())
+    }
+
+    fn send_with(&mut self, msg: Message, wait: Wait) -> Result<(), SendError> {
+        let r = self.try_send(msg, wait);
+        if let Err(e) = r {
+            self.trace_op(TraceOp::SendFailed(e), &msg.header);
+        }
+        r
+    }
+
+    fn try_send(&mut self, mut msg: Message, wait: Wait) -> Result<(), SendError> {
+        // Only the kernel mints reply caps; never pass through one a task filled in.
+        msg.reply = None;
+        self.check_send(&msg)?;
+        let Wait::Until(deadline) = wait else {
+            return self.enqueue(msg);
+        };
+        let dst = msg.header.dst;
+        let free = self.lookup_mailbox(dst)?.mailbox.free();
+        if free > 0 {
+            return self.enqueue(msg);
+        }
+        if self.now() >= deadline {
+            return Err(SendError::Timeout);
+        }
+        let task = self.current_task();
+        if let Ok(ep) = self.lookup(dst) {
+            ep.senders |= 1 << task.index();
+        }
+        self.park(task, TaskState::WaitingSend(dst), Some(deadline));
+        Err(SendError::MailboxFull)
+    }
+
+    fn recv_with(&mut self, dst: EndpointId, wait: Wait) -> Result<Option<Message>, RecvError> {
+        let r = self.try_recv(dst, wait);
+        if let Err(e) = r {
+            self.trace
+                .record_failure(self.now(), TraceOp::RecvFailed(e), dst);
+        }
+        r
+    }
+
+    fn try_recv(&mut self, dst: EndpointId, wait: Wait) -> Result<Option<Message>, RecvError> {
+        self.check_recv(dst)?;
+        let now = self.now();
+        let ep = self.lookup_mailbox(dst)?;
+        if let Some(msg) = ep.mailbox.take() {
+            let senders = core::mem::take(&mut ep.senders);
+            self.wake(senders);
+            return Ok(Some(self.accept(msg)));
+        }
+        let deadline = match wait {
+            Wait::Never => return Ok(None),
+            Wait::Forever => None,
+            Wait::Until(deadline) if now >= deadline => return Err(RecvError::Timeout),
+            Wait::Until(deadline) => Some(deadline),
+        };
+        let task = self.current_task();
+        if let Ok(ep) = self.lookup(dst) {
+            ep.waiters |= 1 << task.index();
+        }
+        self.park(task, TaskState::WaitingRecv(dst), deadline);
+        Ok(None)
+    }
+
+    fn trace_op(&mut self, op: TraceOp, header: &MsgHeader) {
+        let now = self.now();
+        self.trace.record(now, op, header);
+    }
+
+    fn park(&mut self, task: TaskId, state: TaskState, deadline: Option<u64>) {
+        let t = &mut self.tasks[task.index()];
+        t.state = state;
+        t.deadline = deadline;
+    }
+
+    fn current_task(&self) -> TaskId {
+        self.current
+            .expect("ipc: blocking operation outside of a task")
+    }
+
+    fn rights(&self, obj: Object) -> Rights {
+        match self.current {
+            Some(task) => self.tasks[task.index()].caps.rights(obj),
+            None => Rights::ALL,
+        }
+    }
+
+    fn badge(&self, obj: Object) -> Badge {
+        self.current
+            .and_then(|task| self.tasks[task.index()].caps.get(obj))
+            .map_or(Badge::NONE, |c| c.badge)
+    }
+
+    /// Give the current task (if any) every right on an object it just created.
+    fn grant_creator(&mut self, obj: Object) -> Result<(), EndpointError> {
+        let Some(task) = self.current else {
+            return Ok(());
+        };
+        let cap = Capability {
+            obj,
+            rights: Rights::ALL,
+            badge: Badge::NONE,
+        };
+        self.tasks[task.index()]
+            .caps
+            .insert(cap)
+            .map_err(|_| EndpointError::CapTableFull)
+    }
+
+    /// Take every task's capability to a destroyed object, so the slots it used can hold
+    /// new ones.
+    fn revoke(&mut self, obj: Object) {
+        for t in self.tasks.iter_mut() {
+            t.caps.remove(obj);
+        }
+    }
+
+    fn check_send(&self, msg: &Message) -> Result<(), SendError> {
+        check_len(&msg.header)?;
+        let rights = self.rights(msg.header.dst.into());
+        if !rights.contains(Rights::SEND) {
+            return Err(SendError::PermissionDenied);
+        }
+        if let Some(cap) = msg.cap {
+            // Attaching a cap needs GRANT on the endpoint it travels through, and a task
+            // can only pass on rights it holds itself. Only an object's owner (every right)
+            // may mint a new badge; anyone else passes on the badge they were given.
+            let own = self.rights(cap.obj);
+            if !rights.contains(Rights::GRANT)
+                || !own.contains(cap.rights)
+                || (cap.badge != self.badge(cap.obj) && !own.contains(Rights::ALL))
+            {
+                return Err(SendError::PermissionDenied);
+            }
+        }
+        if let Some(grant) = msg.grant {
+            if !rights.contains(Rights::GRANT) {
+                return Err(SendError::PermissionDenied);
+            }
+            if !grant.is_valid() {
+                return Err(SendError::InvalidGrant);
+            }
+            if !self.owns_grant(&grant) {
+                return Err(SendError::ForeignGrant);
+            }
+            if self.grant_mapper.is_none() {
+                return Err(SendError::GrantsUnsupported);
+            }
+        }
+        Ok(())
+    }
+
+    fn check_recv(&self, ep: EndpointId) -> Result<(), RecvError> {
+        if self.rights(ep.into()).contains(Rights::RECV) {
+            Ok(())
+        } else {
+            Err(RecvError::PermissionDenied)
+        }
+    }
+
+    /// Hand a dequeued message to the current task, installing any capability it carries
+    /// and mapping any page grant into its address space.
+    fn accept(&mut self, mut msg: Message) -> Message {
+        self.trace_op(TraceOp::Recv, &msg.header);
+        let Some(task) = self.current else {
+            return msg;
+        };
+        if let Some(cap) = msg.cap {
+            // No room in the receiver's table: the message still arrives, minus the cap.
+            if self.tasks[task.index()].caps.insert(cap).is_err() {
+                msg.cap = None;
+            }
+        }
+        if let Some(grant) = msg.grant.as_mut() {
+            // Same for grants the receiver has no address space left for.
+            let va = self
+                .grant_mapper
+                .and_then(|m| m.map_grant(task.index(), grant.phys, grant.frames));
+            match va {
+                Some(va) => grant.va = va,
+                None => msg.grant = None,
+            }
+        }
+        msg
+    }
+
+    fn enqueue(&mut self, mut msg: Message) -> Result<(), SendError> {
+        msg.badge = self.badge(msg.header.dst.into());
+        self.deliver(msg)
+    }
+
+    /// Queue an already checked and badged message on its destination.
+    fn deliver(&mut self, msg: Message) -> Result<(), SendError> {
+        let ep = self.lookup_mailbox(msg.header.dst)?;
+        ep.mailbox.put(msg)?;
+        let waiters = core::mem::take(&mut ep.waiters);
+        self.wake(waiters);
+        self.trace_op(TraceOp::Send, &msg.header);
+        Ok(())
+    }
+
+    /// Signal `id` without a rights check; `signal` and IRQ delivery both end up here.
+    fn notify(&mut self, id: NotificationId, bits: usize) -> Result<(), Missing> {
+        let n = self.lookup_notification(id)?;
+        n.pending |= bits;
+        let waiters = core::mem::take(&mut n.waiters);
+        self.wake(waiters);
+        Ok(())
+    }
+
+    fn wake(&mut self, tasks: u32) {
+        let mut woken = 0;
+        for (i, t) in self.tasks.iter_mut().enumerate() {
+            // A faulted task only resumes when its pager says so, and a dead one never does.
+            let parked = !matches!(
+                t.state,
+                TaskState::Runnable | TaskState::Faulted | TaskState::Dead
+            );
+            if tasks & (1 << i) != 0 && parked {
+                t.state = TaskState::Runnable;
+                t.deadline = None;
+                woken |= 1 << i;
+                match self.wakers[i].take() {
+                    Some(waker) => waker.wake(),
+                    None => self.woken |= 1 << i,
+                }
+            }
+        }
+        self.forget_waits(woken);
+    }
+
+    /// Clear `tasks` from every waiter and sender mask. A task woken by one object (or
+    /// its deadline) may still be listed on others, which would otherwise wake it out of
+    /// whatever it parks in next.
+    fn forget_waits(&mut self, tasks: u32) {
+        if tasks == 0 {
+            return;
+        }
+        for ep in self.endpoints.iter_mut() {
+            ep.waiters &= !tasks;
+            ep.senders &= !tasks;
+        }
+        for n in self.notifications.iter_mut() {
+            n.waiters &= !tasks;
+        }
+    }
+
+    fn lookup(&mut self, id: EndpointId) -> Result<&mut Endpoint, Missing> {
+        lookup_in(&mut self.endpoints, id.slot(), id.generation())
+    }
+
+    /// Like `lookup`, but for using the endpoint's mailbox, which pipes don't have.
+    fn lookup_mailbox(&mut self, id: EndpointId) -> Result<&mut Endpoint, Missing> {
+        let ep = self.lookup(id)?;
+        if ep.pipe {
+            return Err(Missing::Pipe);
+        }
+        Ok(ep)
+    }
+
+    fn lookup_topic(&mut self, id: TopicId) -> Result<&mut Topic, Missing> {
+        lookup_in(&mut self.topics, id.slot(), id.generation())
+    }
+
+    fn lookup_notification(&mut self, id: NotificationId) -> Result<&mut Notification, Missing> {
+        lookup_in(&mut self.notifications, id.slot(), id.generation())
+    }
+
+    /// Bitmask of tasks currently in `state`.
+    fn parked_on(&self, state: TaskState) -> u32 {
+        self.tasks
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| t.state == state)
+            .fold(0, |mask, (i, _)| mask | 1 << i)
+    }
+}
+
+fn check_len(header: &MsgHeader) -> Result<(), SendError> {
+    if header.len as usize > MAX_PAYLOAD {
+        return Err(SendError::PayloadTooLarge);
+    }
+    Ok(())
 }
 
 pub fn write_u32_le(dst: &mut [u8], v: u32) {
@@ -125,4 +1440,457 @@ pub fn read_u32_le(src: &[u8]) -> u32 {
         | ((src[3] as u32) << 24)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{grant, grant_badged, msg, MockLogger};
+
+    const T0: TaskId = TaskId::new(0);
+    const T1: TaskId = TaskId::new(1);
+
+    fn seqs(mb: &mut Mailbox) -> std::vec::Vec<u32> {
+        core::iter::from_fn(|| mb.take())
+            .map(|m| m.header.seq)
+            .collect()
+    }
+
+    #[test]
+    fn u32_le_round_trip() {
+        let mut buf = [0u8; 4];
+        write_u32_le(&mut buf, 0x1234_5678);
+        assert_eq!(buf, [0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(read_u32_le(&buf), 0x1234_5678);
+    }
+
+    #[test]
+    fn mailbox_is_fifo_and_wraps() {
+        let ep = EndpointId::new(0, 0);
+        let mut mb = Mailbox::new(3);
+        for seq in 0..3 {
+            mb.put(msg(ep, ep, 1, seq)).unwrap();
+        }
+        assert!(matches!(
+            mb.put(msg(ep, ep, 1, 9)),
+            Err(SendError::MailboxFull)
+        ));
+        assert_eq!(mb.take().unwrap().header.seq, 0);
+        mb.put(msg(ep, ep, 1, 3)).unwrap();
+        assert_eq!(seqs(&mut mb), [1, 2, 3]);
+        assert_eq!(mb.free(), 3);
+    }
+
+    #[test]
+    fn mailbox_takes_highest_priority_first_fifo_within() {
+        let ep = EndpointId::new(0, 0);
+        let mut mb = Mailbox::new(MAX_MAILBOX_DEPTH);
+        for (seq, prio) in [(0, 0), (1, 2), (2, 0), (3, 2), (4, 1)] {
+            mb.put(msg(ep, ep, 1, seq).with_prio(prio)).unwrap();
+        }
+        assert_eq!(seqs(&mut mb), [1, 3, 4, 0, 2]);
+    }
+
+    #[test]
+    fn mailbox_drop_oldest_ignores_priority() {
+        let ep = EndpointId::new(0, 0);
+        let mut mb = Mailbox::new(2);
+        mb.put(msg(ep, ep, 1, 0)).unwrap();
+        mb.put(msg(ep, ep, 1, 1).with_prio(5)).unwrap();
+        assert_eq!(mb.drop_oldest().unwrap().header.seq, 0);
+        assert_eq!(seqs(&mut mb), [1]);
+    }
+
+    #[test]
+    fn send_and_recv_in_kernel_context() {
+        let mut r = Router::new();
+        let ep = r.create_endpoint(2).unwrap();
+        r.send(msg(ep, ep, 1, 7)).unwrap();
+        r.send(msg(ep, ep, 1, 8)).unwrap();
+        assert!(matches!(
+            r.send(msg(ep, ep, 1, 9)),
+            Err(SendError::MailboxFull)
+        ));
+        assert_eq!(r.free_slots(ep), Some(0));
+        assert_eq!(r.recv(ep).unwrap().header.seq, 7);
+        assert_eq!(r.recv(ep).unwrap().header.seq, 8);
+        assert!(matches!(r.recv(ep), Err(RecvError::Empty)));
+    }
+
+    #[test]
+    fn oversized_payloads_are_rejected() {
+        let mut r = Router::new();
+        let ep = r.create_endpoint(1).unwrap();
+        let mut m = msg(ep, ep, 1, 0);
+        m.header.len = MAX_PAYLOAD as u8 + 1;
+        assert!(matches!(r.send(m), Err(SendError::PayloadTooLarge)));
+        assert!(matches!(r.recv(ep), Err(RecvError::Empty)));
+    }
+
+    #[test]
+    fn create_endpoint_rejects_bad_depth() {
+        let mut r = Router::new();
+        assert!(matches!(
+            r.create_endpoint(0),
+            Err(EndpointError::InvalidDepth)
+        ));
+        assert!(matches!(
+            r.create_endpoint(MAX_MAILBOX_DEPTH + 1),
+            Err(EndpointError::InvalidDepth)
+        ));
+    }
+
+    #[test]
+    fn destroyed_endpoint_id_goes_stale() {
+        let mut r = Router::new();
+        let old = r.create_endpoint(1).unwrap();
+        r.destroy_endpoint(old).unwrap();
+        let new = r.create_endpoint(1).unwrap();
+        assert_eq!(old.slot(), new.slot());
+        assert_ne!(old, new);
+        assert!(matches!(
+            r.send(msg(old, old, 1, 0)),
+            Err(SendError::Closed)
+        ));
+        assert!(matches!(r.recv(old), Err(RecvError::Closed)));
+        let bogus = EndpointId::new(MAX_ENDPOINTS, 0);
+        assert!(matches!(r.recv(bogus), Err(RecvError::NoSuchEndpoint)));
+    }
+
+    #[test]
+    fn never_created_endpoint_is_not_closed() {
+        let mut r = Router::new();
+        let ep = r.create_endpoint(1).unwrap();
+        let unused = EndpointId::from_raw(ep.raw() + 1);
+        assert!(matches!(
+            r.send(msg(ep, unused, 1, 0)),
+            Err(SendError::NoSuchEndpoint)
+        ));
+        assert!(matches!(r.recv(unused), Err(RecvError::NoSuchEndpoint)));
+        r.destroy_endpoint(ep).unwrap();
+        assert!(matches!(r.recv(ep), Err(RecvError::Closed)));
+    }
+
+    #[test]
+    fn destroying_objects_frees_cap_slots() {
+        let mut r = Router::new();
+        let ep = r.create_endpoint(1).unwrap();
+        grant(&mut r, 1, ep, Rights::SEND);
+        r.set_current(T0);
+        for _ in 0..2 * crate::cap::MAX_CAPS {
+            let ep = r.create_endpoint(1).unwrap();
+            r.destroy_endpoint(ep).unwrap();
+            let n = r.create_notification().unwrap();
+            r.destroy_notification(n).unwrap();
+        }
+        assert_eq!(r.tasks[T1.index()].caps.rights(ep.into()), Rights::SEND);
+        r.current = None;
+        r.destroy_endpoint(ep).unwrap();
+        assert_eq!(r.tasks[T1.index()].caps.rights(ep.into()), Rights::NONE);
+    }
+
+    #[test]
+    fn tasks_need_rights() {
+        let mut r = Router::new();
+        let ep = r.create_endpoint(1).unwrap();
+        grant(&mut r, 0, ep, Rights::SEND);
+        r.set_current(T0);
+        r.send(msg(ep, ep, 1, 0)).unwrap();
+        assert!(matches!(r.recv(ep), Err(RecvError::PermissionDenied)));
+        r.set_current(T1);
+        assert!(matches!(
+            r.send(msg(ep, ep, 1, 0)),
+            Err(SendError::PermissionDenied)
+        ));
+        assert!(r
+            .install_cap(
+                T1,
+                Capability {
+                    obj: ep.into(),
+                    rights: Rights::ALL,
+                    badge: Badge::NONE,
+                }
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn recv_blocking_parks_until_send() {
+        let mut r = Router::new();
+        let ep = r.create_endpoint(1).unwrap();
+        grant(&mut r, 0, ep, Rights::RECV);
+        grant(&mut r, 1, ep, Rights::SEND);
+        r.set_current(T0);
+        assert!(r.recv_blocking(ep).unwrap().is_none());
+        assert!(!r.is_runnable(T0));
+
+        r.set_current(T1);
+        r.send(msg(ep, ep, 1, 3)).unwrap();
+        assert!(r.is_runnable(T0));
+        assert_eq!(r.take_woken(), 1 << T0.index());
+
+        r.set_current(T0);
+        assert_eq!(r.recv_blocking(ep).unwrap().unwrap().header.seq, 3);
+    }
+
+    #[test]
+    fn call_reply_round_trip() {
+        let mut r = Router::new();
+        let ep = r.create_endpoint(1).unwrap();
+        grant(&mut r, 0, ep, Rights::SEND);
+        grant(&mut r, 1, ep, Rights::RECV);
+
+        r.set_current(T0);
+        r.call(msg(ep, ep, 1, 1)).unwrap();
+        assert!(!r.is_runnable(T0));
+
+        r.set_current(T1);
+        let req = r.recv(ep).unwrap();
+        let cap = req.reply.unwrap();
+        r.reply(cap, msg(ep, ep, 2, 1)).unwrap();
+        assert!(matches!(
+            r.reply(cap, msg(ep, ep, 2, 1)),
+            Err(ReplyError::StaleReplyCap)
+        ));
+        assert!(r.is_runnable(T0));
+
+        r.set_current(T0);
+        assert_eq!(r.take_reply().unwrap().unwrap().header.ty, MsgTag(2));
+        assert!(r.take_reply().unwrap().is_none());
+    }
+
+    #[test]
+    fn destroying_an_endpoint_fails_queued_calls() {
+        let mut r = Router::new();
+        let ep = r.create_endpoint(1).unwrap();
+        grant(&mut r, 0, ep, Rights::SEND);
+        r.set_current(T0);
+        r.call(msg(ep, ep, 1, 0)).unwrap();
+        assert!(!r.is_runnable(T0));
+
+        r.current = None;
+        r.destroy_endpoint(ep).unwrap();
+        assert!(r.is_runnable(T0));
+        r.set_current(T0);
+        assert!(matches!(r.take_reply(), Err(RecvError::Closed)));
+        assert!(r.take_reply().unwrap().is_none());
+    }
+
+    #[test]
+    fn send_strips_forged_reply_caps() {
+        let mut r = Router::new();
+        let ep = r.create_endpoint(1).unwrap();
+        let mut m = msg(ep, ep, 1, 0);
+        m.reply = Some(ReplyCap {
+            task: T0,
+            nonce: 1,
+            ep,
+        });
+        r.send(m).unwrap();
+        assert!(r.recv(ep).unwrap().reply.is_none());
+    }
+
+    #[test]
+    fn kernel_stamps_sender_badge() {
+        let mut r = Router::new();
+        let ep = r.create_endpoint(2).unwrap();
+        grant_badged(&mut r, 0, ep, Rights::SEND | Rights::GRANT, Badge(5));
+        grant_badged(&mut r, 1, ep, Rights::ALL, Badge(6));
+        r.set_current(T0);
+        let mut m = msg(ep, ep, 1, 0);
+        m.badge = Badge(6);
+        r.send(m).unwrap();
+
+        // Only the owner may hand out a cap with a new badge.
+        let mut m = msg(ep, ep, 1, 1);
+        m.cap = Some(Capability {
+            obj: ep.into(),
+            rights: Rights::SEND,
+            badge: Badge(9),
+        });
+        assert!(matches!(r.send(m), Err(SendError::PermissionDenied)));
+
+        r.set_current(T1);
+        assert_eq!(r.recv(ep).unwrap().badge, Badge(5));
+        r.send(m).unwrap();
+    }
+
+    #[test]
+    fn recv_timeout_expires() {
+        let mut r = Router::new();
+        let ep = r.create_endpoint(1).unwrap();
+        grant(&mut r, 0, ep, Rights::RECV);
+        r.set_current(T0);
+        let deadline = r.now() + 2;
+        assert!(r.recv_timeout(ep, deadline).unwrap().is_none());
+        r.advance_tick();
+        assert!(!r.is_runnable(T0));
+        r.advance_tick();
+        assert!(r.is_runnable(T0));
+        assert!(matches!(
+            r.recv_timeout(ep, deadline),
+            Err(RecvError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn call_timeout_reports_once_and_refuses_late_reply() {
+        let mut r = Router::new();
+        let ep = r.create_endpoint(1).unwrap();
+        grant(&mut r, 0, ep, Rights::SEND | Rights::RECV);
+        r.set_current(T0);
+        r.call_timeout(msg(ep, ep, 1, 0), r.now() + 1).unwrap();
+        let cap = r.recv(ep).unwrap().reply.unwrap();
+        r.advance_tick();
+        assert!(r.is_runnable(T0));
+        assert!(matches!(r.take_reply(), Err(RecvError::Timeout)));
+        assert!(r.take_reply().unwrap().is_none());
+        assert!(r.reply(cap, msg(ep, ep, 2, 0)).is_err());
+    }
+
+    #[test]
+    fn timed_out_wait_does_not_wake_a_later_call() {
+        let mut r = Router::new();
+        let stale = r.create_endpoint(1).unwrap();
+        let ep = r.create_endpoint(1).unwrap();
+        grant(&mut r, 0, stale, Rights::RECV);
+        grant(&mut r, 0, ep, Rights::SEND);
+        grant(&mut r, 1, ep, Rights::RECV);
+
+        r.set_current(T0);
+        assert!(r.recv_timeout(stale, r.now() + 1).unwrap().is_none());
+        r.advance_tick();
+        assert!(r.is_runnable(T0));
+        r.call(msg(ep, ep, 1, 0)).unwrap();
+
+        // A stray send to the endpoint T0 timed out on mustn't end its call.
+        r.current = None;
+        r.send(msg(stale, stale, 1, 0)).unwrap();
+        assert!(!r.is_runnable(T0));
+
+        r.set_current(T1);
+        let cap = r.recv(ep).unwrap().reply.unwrap();
+        r.reply(cap, msg(ep, ep, 2, 0)).unwrap();
+        r.set_current(T0);
+        assert_eq!(r.take_reply().unwrap().unwrap().header.ty, MsgTag(2));
+    }
+
+    #[test]
+    fn signal_wakes_waiter_and_accumulates_bits() {
+        let mut r = Router::new();
+        let n = r.create_notification().unwrap();
+        grant(&mut r, 0, n, Rights::RECV);
+        grant(&mut r, 1, n, Rights::SEND);
+        r.set_current(T0);
+        assert_eq!(r.wait(n).unwrap(), 0);
+        assert!(!r.is_runnable(T0));
+
+        r.set_current(T1);
+        r.signal(n, 0b01);
+        r.signal(n, 0b10);
+        assert!(r.is_runnable(T0));
+
+        r.set_current(T0);
+        assert_eq!(r.wait(n).unwrap(), 0b11);
+        assert_eq!(r.poll(n).unwrap(), 0);
+    }
+
+    #[test]
+    fn publish_fans_out_to_subscribers() {
+        let mut r = Router::new();
+        let topic = r.create_topic(OverflowPolicy::DropNewest).unwrap();
+        let a = r.create_endpoint(1).unwrap();
+        let b = r.create_endpoint(1).unwrap();
+        r.subscribe(topic, a).unwrap();
+        r.subscribe(topic, b).unwrap();
+        r.subscribe(topic, b).unwrap();
+        assert_eq!(r.publish(topic, msg(a, a, 1, 1)).unwrap(), 2);
+        assert_eq!(r.recv(a).unwrap().header.dst, a);
+        // `b` is still full, so only `a` gets the next one.
+        assert_eq!(r.publish(topic, msg(a, a, 1, 2)).unwrap(), 1);
+        assert_eq!(r.recv(b).unwrap().header.seq, 1);
+        assert_eq!(r.recv(a).unwrap().header.seq, 2);
+    }
+
+    #[test]
+    fn publish_drop_oldest_keeps_newest() {
+        let mut r = Router::new();
+        let topic = r.create_topic(OverflowPolicy::DropOldest).unwrap();
+        let ep = r.create_endpoint(1).unwrap();
+        r.subscribe(topic, ep).unwrap();
+        r.publish(topic, msg(ep, ep, 1, 1)).unwrap();
+        assert_eq!(r.publish(topic, msg(ep, ep, 1, 2)).unwrap(), 1);
+        assert_eq!(r.recv(ep).unwrap().header.seq, 2);
+    }
+
+    #[test]
+    fn publish_block_parks_publisher_until_drained() {
+        let mut r = Router::new();
+        let topic = r.create_topic(OverflowPolicy::Block).unwrap();
+        let ep = r.create_endpoint(1).unwrap();
+        r.subscribe(topic, ep).unwrap();
+        grant(&mut r, 0, topic, Rights::SEND);
+        grant(&mut r, 1, ep, Rights::RECV);
+
+        r.set_current(T0);
+        r.publish(topic, msg(ep, ep, 1, 1)).unwrap();
+        assert!(matches!(
+            r.publish(topic, msg(ep, ep, 1, 2)),
+            Err(SendError::MailboxFull)
+        ));
+        assert!(!r.is_runnable(T0));
+
+        r.set_current(T1);
+        r.recv(ep).unwrap();
+        assert!(r.is_runnable(T0));
+    }
+
+    #[test]
+    fn blocked_publisher_is_not_woken_by_a_second_subscriber() {
+        let mut r = Router::new();
+        let topic = r.create_topic(OverflowPolicy::Block).unwrap();
+        let a = r.create_endpoint(1).unwrap();
+        let b = r.create_endpoint(1).unwrap();
+        let other = r.create_endpoint(1).unwrap();
+        r.subscribe(topic, a).unwrap();
+        r.subscribe(topic, b).unwrap();
+        r.publish(topic, msg(a, a, 1, 1)).unwrap();
+        grant(&mut r, 0, topic, Rights::SEND);
+        grant(&mut r, 0, other, Rights::RECV);
+
+        r.set_current(T0);
+        assert!(matches!(
+            r.publish(topic, msg(a, a, 1, 2)),
+            Err(SendError::MailboxFull)
+        ));
+        r.current = None;
+        r.recv(a).unwrap();
+        assert!(r.is_runnable(T0));
+
+        r.set_current(T0);
+        assert!(r.recv_blocking(other).unwrap().is_none());
+        r.current = None;
+        r.recv(b).unwrap();
+        assert!(!r.is_runnable(T0));
+    }
+
+    #[test]
+    fn trace_records_sends_and_failures() {
+        let mut r = Router::new();
+        let ep = r.create_endpoint(1).unwrap();
+        r.send(msg(ep, ep, 1, 42)).unwrap();
+        let _ = r.send(msg(ep, ep, 1, 43));
+        let log = MockLogger::default();
+        r.dump_trace(&log);
+        let out = log.output();
+        if cfg!(feature = "ipc-trace") {
+            assert!(
+                out.contains("send   0x0000 -> 0x0000 ty=1 seq=42\n"),
+                "{out}"
+            );
+            assert!(out.contains("seq=43 (MailboxFull)"), "{out}");
+        } else {
+            assert!(out.contains("disabled"));
+        }
+    }
+}
+
 
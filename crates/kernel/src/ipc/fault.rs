@@ -0,0 +1,463 @@
+//! Faults taken by tasks, forwarded to a pager as IPC.
+//!
+//! A task may have a pager: an endpoint that is sent a `Fault` when the task takes a
+//! synchronous abort. The arch exception handler records the fault with
+//! `FaultSlots::raise` and stops running the task; nothing else is safe from exception
+//! context. The scheduler then calls `Router::deliver_faults`, which sends the `Fault`
+//! with a reply cap. The task stays suspended until the pager answers through that cap
+//! with `FaultReply::Resume` or `FaultReply::Kill`. A task without a pager is killed.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use super::{EndpointId, Message, Missing, RecvError, ReplyCap, Router, SendError, TaskState};
+use crate::cap::Rights;
+use crate::message::{ipc_message, wire_enum, Wire};
+use crate::sched::{TaskId, MAX_TASKS};
+
+wire_enum! {
+    /// What the faulting access was trying to do.
+    pub enum FaultAccess {
+        Read = 0,
+        Write = 1,
+        Exec = 2,
+    }
+}
+
+/// A fault as decoded by the arch exception handler.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FaultInfo {
+    /// Faulting virtual address.
+    pub addr: u64,
+    pub access: FaultAccess,
+    /// Architecture-specific exception class (ESR_EL1.EC on aarch64).
+    pub class: u8,
+}
+
+ipc_message! {
+    /// Sent by the kernel to a task's pager when the task faults. Comes with a reply cap.
+    pub struct Fault: 4 {
+        pub task: u8,
+        pub addr: u64,
+        pub access: FaultAccess,
+        pub class: u8,
+    }
+}
+
+ipc_message! {
+    /// The pager's answer to a `Fault`. Any other reply kills the task.
+    pub enum FaultReply: 5 {
+        Resume = 0,
+        Kill = 1,
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FaultError {
+    NoSuchEndpoint,
+    Closed,
+    /// The pager endpoint belongs to a pipe, which can't take messages.
+    IsPipe,
+    PermissionDenied,
+}
+
+impl From<Missing> for FaultError {
+    fn from(m: Missing) -> Self {
+        match m {
+            Missing::NoSuchEndpoint => FaultError::NoSuchEndpoint,
+            Missing::Closed => FaultError::Closed,
+            Missing::Pipe => FaultError::IsPipe,
+        }
+    }
+}
+
+/// Where a task's last fault stands, for whoever keeps it off the CPU.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FaultStatus {
+    /// Waiting for its pager; the task must not run.
+    Suspended,
+    /// No fault outstanding, or the pager resumed the task.
+    Resumed,
+    /// The pager killed the task (or it had none); it never runs again.
+    Killed,
+}
+
+/// The exception handler's side of fault handling (a handler-side table, see `ipc`). A
+/// task has at most one outstanding fault, since it doesn't run again until that one is
+/// resolved.
+pub struct FaultSlots {
+    addr: [AtomicU64; MAX_TASKS],
+    // Exception class in bits 0..8, `FaultAccess` in bits 8..16.
+    info: [AtomicU32; MAX_TASKS],
+    // Bitmask of tasks with a fault not yet handed to `deliver_faults`.
+    pending: AtomicU32,
+    // Bitmask of tasks the arch must not run: faulted and not resumed (or killed).
+    suspended: AtomicU32,
+    // Bitmask of tasks killed over their last fault.
+    killed: AtomicU32,
+}
+
+impl FaultSlots {
+    pub const fn new() -> Self {
+        Self {
+            addr: [const { AtomicU64::new(0) }; MAX_TASKS],
+            info: [const { AtomicU32::new(0) }; MAX_TASKS],
+            pending: AtomicU32::new(0),
+            suspended: AtomicU32::new(0),
+            killed: AtomicU32::new(0),
+        }
+    }
+
+    /// Called from the arch exception handler when `task` takes `fault`. The task is
+    /// suspended from here on; the caller must switch away from it.
+    pub fn raise(&self, task: TaskId, fault: FaultInfo) {
+        let i = task.index();
+        self.addr[i].store(fault.addr, Ordering::Relaxed);
+        let info = fault.class as u32 | (fault.access as u32) << 8;
+        self.info[i].store(info, Ordering::Relaxed);
+        self.killed.fetch_and(!(1 << i), Ordering::AcqRel);
+        self.suspended.fetch_or(1 << i, Ordering::AcqRel);
+        self.pending.fetch_or(1 << i, Ordering::AcqRel);
+    }
+
+    pub fn status(&self, task: TaskId) -> FaultStatus {
+        let bit = 1 << task.index();
+        if self.suspended.load(Ordering::Acquire) & bit != 0 {
+            FaultStatus::Suspended
+        } else if self.killed.load(Ordering::Acquire) & bit != 0 {
+            FaultStatus::Killed
+        } else {
+            FaultStatus::Resumed
+        }
+    }
+
+    fn get(&self, task: TaskId) -> FaultInfo {
+        let i = task.index();
+        let info = self.info[i].load(Ordering::Relaxed);
+        FaultInfo {
+            addr: self.addr[i].load(Ordering::Relaxed),
+            access: FaultAccess::get(&[(info >> 8) as u8]).unwrap_or(FaultAccess::Read),
+            class: info as u8,
+        }
+    }
+}
+
+impl Router {
+    /// Register the table the arch exception handler records faults in.
+    pub fn set_fault_slots(&mut self, slots: &'static FaultSlots) {
+        self.fault_slots = Some(slots);
+    }
+
+    /// Have `hook` called whenever a pager resumes a task, after its `FaultStatus` has
+    /// turned `Resumed`, so that a thread acting as the task can be scheduled right away.
+    pub fn set_resume_hook(&mut self, hook: fn()) {
+        self.on_resume = Some(hook);
+    }
+
+    /// Send `task`'s faults to `pager` from now on.
+    ///
+    /// Needs `Rights::SEND` on `pager`. A task may only pick its own pager; the kernel
+    /// may set anyone's.
+    pub fn set_pager(&mut self, task: TaskId, pager: EndpointId) -> Result<(), FaultError> {
+        if self.current.is_some_and(|c| c != task) {
+            return Err(FaultError::PermissionDenied);
+        }
+        self.lookup_mailbox(pager)?;
+        if !self.rights(pager.into()).contains(Rights::SEND) {
+            return Err(FaultError::PermissionDenied);
+        }
+        self.tasks[task.index()].pager = Some(pager);
+        Ok(())
+    }
+
+    /// Hand recorded faults to their pagers. Called by the scheduler before it polls.
+    ///
+    /// A fault that can't be sent because the pager's mailbox is full stays pending (and
+    /// the task suspended) until a later call.
+    pub fn deliver_faults(&mut self) {
+        let Some(slots) = self.fault_slots else {
+            return;
+        };
+        let pending = slots.pending.swap(0, Ordering::AcqRel);
+        for i in 0..MAX_TASKS {
+            if pending & (1 << i) == 0 {
+                continue;
+            }
+            let task = TaskId::new(i);
+            if !self.forward_fault(task, slots.get(task)) {
+                slots.pending.fetch_or(1 << i, Ordering::AcqRel);
+            }
+        }
+    }
+
+    /// Suspend `task` and send its pager a `Fault`. Returns false if the pager's mailbox
+    /// is full and the fault should be retried.
+    ///
+    /// A call the task was waiting on is failed with `RecvError::Aborted`: the fault's
+    /// reply cap takes over the task's nonce, so the callee's reply would be refused.
+    /// The nonce moves on as soon as the task is suspended, even if the pager can't be
+    /// sent the fault yet, so no earlier reply cap can answer it.
+    fn forward_fault(&mut self, task: TaskId, fault: FaultInfo) -> bool {
+        let t = &mut self.tasks[task.index()];
+        if t.state == TaskState::WaitingReply {
+            t.call_error = Some(RecvError::Aborted);
+        }
+        t.reply_nonce = t.reply_nonce.wrapping_add(1);
+        let nonce = t.reply_nonce;
+        self.park(task, TaskState::Faulted, None);
+        // Whatever it was parked on no longer concerns it.
+        self.forget_waits(1 << task.index());
+        let Some(pager) = self.tasks[task.index()].pager else {
+            self.kill(task);
+            return true;
+        };
+        let body = Fault {
+            task: task.index() as u8,
+            addr: fault.addr,
+            access: fault.access,
+            class: fault.class,
+        };
+        let mut msg = Message::encode(pager, pager, nonce, &body);
+        msg.reply = Some(ReplyCap {
+            task,
+            nonce,
+            ep: pager,
+        });
+        match self.deliver(msg) {
+            Ok(()) => true,
+            Err(SendError::MailboxFull) => false,
+            // The pager is gone, so nobody will ever resume the task.
+            Err(_) => {
+                self.kill(task);
+                true
+            }
+        }
+    }
+
+    /// Act on the pager's reply to a fault.
+    pub(super) fn resolve_fault(&mut self, task: TaskId, reply: Option<FaultReply>) {
+        if reply != Some(FaultReply::Resume) {
+            self.kill(task);
+            return;
+        }
+        self.tasks[task.index()].state = TaskState::Runnable;
+        self.woken |= 1 << task.index();
+        if let Some(slots) = self.fault_slots {
+            slots
+                .suspended
+                .fetch_and(!(1 << task.index()), Ordering::AcqRel);
+        }
+        if let Some(hook) = self.on_resume {
+            hook();
+        }
+    }
+
+    pub(super) fn kill(&mut self, task: TaskId) {
+        let t = &mut self.tasks[task.index()];
+        t.state = TaskState::Dead;
+        t.deadline = None;
+        t.reply = None;
+        if let Some(slots) = self.fault_slots {
+            // Killed before suspended is cleared, so the task is never seen as resumed.
+            let bit = 1 << task.index();
+            slots.killed.fetch_or(bit, Ordering::AcqRel);
+            slots.suspended.fetch_and(!bit, Ordering::AcqRel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipc::ReplyError;
+    use crate::testutil::{grant, leak};
+
+    const FAULT: FaultInfo = FaultInfo {
+        addr: 0xdead_b000,
+        access: FaultAccess::Write,
+        class: 0x25,
+    };
+
+    static RESUMES: AtomicU32 = AtomicU32::new(0);
+
+    fn count_resume() {
+        RESUMES.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn setup() -> (Router, &'static FaultSlots, EndpointId) {
+        let slots = leak(FaultSlots::new());
+        let mut r = Router::new();
+        r.set_fault_slots(slots);
+        let pager = r.create_endpoint(1).unwrap();
+        (r, slots, pager)
+    }
+
+    #[test]
+    fn pager_resumes_or_kills() {
+        let (mut r, slots, pager) = setup();
+        let task = TaskId::new(0);
+        r.set_pager(task, pager).unwrap();
+
+        slots.raise(task, FAULT);
+        assert_eq!(slots.status(task), FaultStatus::Suspended);
+        r.deliver_faults();
+        assert!(!r.is_runnable(task));
+
+        let msg = r.recv(pager).unwrap();
+        let fault = msg.decode::<Fault>().unwrap();
+        assert_eq!(
+            fault,
+            Fault {
+                task: 0,
+                addr: 0xdead_b000,
+                access: FaultAccess::Write,
+                class: 0x25,
+            }
+        );
+        let cap = msg.reply.unwrap();
+        let resume = Message::encode(pager, pager, 0, &FaultReply::Resume);
+        r.set_resume_hook(count_resume);
+        r.reply(cap, resume).unwrap();
+        assert_eq!(RESUMES.load(Ordering::Relaxed), 1);
+        assert!(r.is_runnable(task));
+        assert_eq!(slots.status(task), FaultStatus::Resumed);
+        assert_eq!(r.take_woken(), 1);
+        assert_eq!(r.reply(cap, resume), Err(ReplyError::StaleReplyCap));
+
+        slots.raise(task, FAULT);
+        r.deliver_faults();
+        let cap = r.recv(pager).unwrap().reply.unwrap();
+        let kill = Message::encode(pager, pager, 0, &FaultReply::Kill);
+        r.reply(cap, kill).unwrap();
+        assert!(!r.is_runnable(task));
+        assert_eq!(slots.status(task), FaultStatus::Killed);
+        assert_eq!(RESUMES.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn killing_clears_the_suspended_bit() {
+        let (mut r, slots, _) = setup();
+        let task = TaskId::new(3);
+        slots.raise(task, FAULT);
+        r.deliver_faults();
+        assert_eq!(slots.suspended.load(Ordering::Relaxed), 0);
+        assert_eq!(slots.status(task), FaultStatus::Killed);
+
+        // A new fault for the same task starts out suspended again.
+        slots.raise(task, FAULT);
+        assert_eq!(slots.status(task), FaultStatus::Suspended);
+    }
+
+    #[test]
+    fn fault_aborts_a_pending_call() {
+        let (mut r, slots, pager) = setup();
+        let ep = r.create_endpoint(1).unwrap();
+        grant(&mut r, 0, ep, Rights::SEND);
+        r.set_pager(TaskId::new(0), pager).unwrap();
+
+        r.set_current(TaskId::new(0));
+        r.call(Message::encode(ep, ep, 0, &FaultReply::Kill))
+            .unwrap();
+        r.current = None;
+        let call = r.recv(ep).unwrap().reply.unwrap();
+        slots.raise(TaskId::new(0), FAULT);
+        r.deliver_faults();
+        let fault = r.recv(pager).unwrap().reply.unwrap();
+
+        let answer = Message::encode(ep, ep, 0, &FaultReply::Resume);
+        assert_eq!(r.reply(call, answer), Err(ReplyError::StaleReplyCap));
+        r.reply(fault, answer).unwrap();
+        r.set_current(TaskId::new(0));
+        assert!(matches!(r.take_reply(), Err(RecvError::Aborted)));
+        assert!(matches!(r.take_reply(), Ok(None)));
+    }
+
+    #[test]
+    fn answered_call_cap_cannot_resume_an_undelivered_fault() {
+        let (mut r, slots, pager) = setup();
+        let ep = r.create_endpoint(1).unwrap();
+        grant(&mut r, 0, ep, Rights::SEND);
+        r.set_pager(TaskId::new(0), pager).unwrap();
+        r.send(Message::encode(pager, pager, 0, &FaultReply::Kill))
+            .unwrap();
+
+        r.set_current(TaskId::new(0));
+        r.call(Message::encode(ep, ep, 0, &FaultReply::Kill))
+            .unwrap();
+        r.current = None;
+        let call = r.recv(ep).unwrap().reply.unwrap();
+        let resume = Message::encode(ep, ep, 0, &FaultReply::Resume);
+        r.reply(call, resume).unwrap();
+
+        // The pager's mailbox is full, so the fault stays undelivered.
+        slots.raise(TaskId::new(0), FAULT);
+        r.deliver_faults();
+        assert_eq!(r.reply(call, resume), Err(ReplyError::StaleReplyCap));
+        assert_eq!(slots.status(TaskId::new(0)), FaultStatus::Suspended);
+    }
+
+    #[test]
+    fn faulted_tasks_ignore_other_wakeups() {
+        let (mut r, slots, pager) = setup();
+        let ep = r.create_endpoint(1).unwrap();
+        grant(&mut r, 0, ep, Rights::RECV);
+        r.set_pager(TaskId::new(0), pager).unwrap();
+
+        r.set_current(TaskId::new(0));
+        assert!(matches!(r.recv_blocking(ep), Ok(None)));
+        slots.raise(TaskId::new(0), FAULT);
+        r.deliver_faults();
+
+        r.current = None;
+        r.send(Message::encode(ep, ep, 0, &FaultReply::Resume))
+            .unwrap();
+        assert!(!r.is_runnable(TaskId::new(0)));
+    }
+
+    #[test]
+    fn no_pager_kills_and_full_pager_retries() {
+        let (mut r, slots, pager) = setup();
+        slots.raise(TaskId::new(1), FAULT);
+        r.deliver_faults();
+        assert!(!r.is_runnable(TaskId::new(1)));
+        assert_eq!(slots.status(TaskId::new(1)), FaultStatus::Killed);
+
+        r.set_pager(TaskId::new(2), pager).unwrap();
+        r.send(Message::encode(pager, pager, 0, &FaultReply::Kill))
+            .unwrap();
+        slots.raise(TaskId::new(2), FAULT);
+        r.deliver_faults();
+        r.recv(pager).unwrap();
+        r.deliver_faults();
+        assert!(r.recv(pager).unwrap().decode::<Fault>().is_some());
+    }
+
+    #[test]
+    fn destroying_the_pager_kills_tasks_with_queued_faults() {
+        let (mut r, slots, pager) = setup();
+        r.set_pager(TaskId::new(0), pager).unwrap();
+        slots.raise(TaskId::new(0), FAULT);
+        r.deliver_faults();
+        r.destroy_endpoint(pager).unwrap();
+        assert_eq!(slots.status(TaskId::new(0)), FaultStatus::Killed);
+    }
+
+    #[test]
+    fn tasks_only_set_their_own_pager() {
+        let (mut r, _, pager) = setup();
+        grant(&mut r, 0, pager, Rights::SEND);
+        r.set_current(TaskId::new(0));
+        assert_eq!(
+            r.set_pager(TaskId::new(1), pager),
+            Err(FaultError::PermissionDenied)
+        );
+        r.set_pager(TaskId::new(0), pager).unwrap();
+
+        r.set_current(TaskId::new(1));
+        assert_eq!(
+            r.set_pager(TaskId::new(1), pager),
+            Err(FaultError::PermissionDenied)
+        );
+    }
+}
+
+
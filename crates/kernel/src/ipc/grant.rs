@@ -0,0 +1,186 @@
+//! Ownership of the frames tasks lend each other with page grants.
+//!
+//! Holding `Rights::GRANT` on an endpoint only lets a task lend frames it owns. A task
+//! gets frames from `Router::alloc_grant`, which takes them from the platform's
+//! `FrameAllocator` and records the task as their owner; `check_send` refuses a grant
+//! that doesn't lie within frames the sender owns with `SendError::ForeignGrant`.
+//! Lending frames doesn't transfer ownership, so the sender can reuse them for later
+//! grants and is the one who frees them.
+
+use hal::mem::FrameAllocator;
+
+use super::{PageGrant, Router, MAX_GRANT_FRAMES, PAGE_SIZE};
+use crate::sched::TaskId;
+
+// Number of allocations `alloc_grant` can have outstanding across all tasks.
+pub const MAX_FRAME_RUNS: usize = 16;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum GrantError {
+    /// No `FrameAllocator` is registered on this platform.
+    Unsupported,
+    /// The frame count is zero or larger than `MAX_GRANT_FRAMES`.
+    InvalidSize,
+    OutOfMemory,
+    /// `MAX_FRAME_RUNS` allocations are already outstanding.
+    TableFull,
+    /// The frames weren't allocated by `alloc_grant` to the current task.
+    NotOwner,
+}
+
+/// Frames handed out by `alloc_grant`, and who to.
+#[derive(Copy, Clone)]
+pub(super) struct FrameRun {
+    phys: u64,
+    frames: usize,
+    // `None` when the kernel allocated them before the scheduler started.
+    owner: Option<TaskId>,
+}
+
+impl FrameRun {
+    fn contains(&self, grant: &PageGrant) -> bool {
+        let end = self.phys + self.frames as u64 * PAGE_SIZE;
+        // `grant` comes from the task, so its end may not even fit in a `u64`.
+        let grant_end = grant.phys.checked_add(grant.bytes() as u64);
+        grant.phys >= self.phys && grant_end.is_some_and(|e| e <= end)
+    }
+}
+
+impl Router {
+    /// Register where `alloc_grant` takes frames from.
+    pub fn set_frame_allocator(&mut self, frames: &'static dyn FrameAllocator) {
+        self.frames = Some(frames);
+    }
+
+    /// Allocate `frames` contiguous frames for the current task to lend with page grants.
+    pub fn alloc_grant(&mut self, frames: usize) -> Result<PageGrant, GrantError> {
+        let allocator = self.frames.ok_or(GrantError::Unsupported)?;
+        if frames == 0 || frames > MAX_GRANT_FRAMES {
+            return Err(GrantError::InvalidSize);
+        }
+        let slot = self
+            .frame_runs
+            .iter()
+            .position(Option::is_none)
+            .ok_or(GrantError::TableFull)?;
+        let phys = allocator
+            .alloc_frames(frames)
+            .ok_or(GrantError::OutOfMemory)?;
+        self.frame_runs[slot] = Some(FrameRun {
+            phys,
+            frames,
+            owner: self.current,
+        });
+        Ok(PageGrant::new(phys, frames))
+    }
+
+    /// Give back frames from `alloc_grant`. Only their owner may free them, and only
+    /// whole: `grant` must be what `alloc_grant` returned.
+    ///
+    /// Mappings made by earlier grants aren't torn down, so the frames should no longer
+    /// be in use by anyone they were lent to.
+    pub fn free_grant(&mut self, grant: PageGrant) -> Result<(), GrantError> {
+        let allocator = self.frames.ok_or(GrantError::Unsupported)?;
+        let current = self.current;
+        let slot = self
+            .frame_runs
+            .iter_mut()
+            .find(|r| {
+                r.is_some_and(|r| {
+                    r.owner == current && r.phys == grant.phys && r.frames == grant.frames
+                })
+            })
+            .ok_or(GrantError::NotOwner)?;
+        *slot = None;
+        allocator.free_frames(grant.phys, grant.frames);
+        Ok(())
+    }
+
+    /// Whether the current task may lend the frames `grant` names.
+    pub(super) fn owns_grant(&self, grant: &PageGrant) -> bool {
+        self.frame_runs
+            .iter()
+            .flatten()
+            .any(|r| r.owner == self.current && r.contains(grant))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hal::mem::GrantMapper;
+
+    use super::*;
+    use crate::cap::Rights;
+    use crate::ipc::SendError;
+    use crate::testutil::{frames, grant, msg};
+
+    /// Maps every grant at its physical address.
+    struct IdentityMapper;
+
+    impl GrantMapper for IdentityMapper {
+        fn map_grant(&self, _space: usize, phys: u64, _frames: usize) -> Option<u64> {
+            Some(phys)
+        }
+    }
+
+    #[test]
+    fn only_owned_frames_can_be_granted() {
+        let mut r = Router::new();
+        r.set_grant_mapper(&IdentityMapper);
+        let allocator = frames(4);
+        r.set_frame_allocator(allocator);
+        let ep = r.create_endpoint(4).unwrap();
+        for task in 0..2 {
+            grant(&mut r, task, ep, Rights::SEND | Rights::GRANT);
+        }
+        grant(&mut r, 2, ep, Rights::RECV);
+
+        r.set_current(TaskId::new(0));
+        let mine = r.alloc_grant(2).unwrap();
+        let mut m = msg(ep, ep, 1, 1);
+        m.grant = Some(mine);
+        r.send(m).unwrap();
+        // Part of an owned run is fine too.
+        m.grant = Some(PageGrant::new(mine.phys + PAGE_SIZE, 1));
+        r.send(m).unwrap();
+        // Frames nobody allocated.
+        m.grant = Some(PageGrant::new(mine.phys + 2 * PAGE_SIZE, 1));
+        assert_eq!(r.send(m), Err(SendError::ForeignGrant));
+
+        // Someone else's frames.
+        r.set_current(TaskId::new(1));
+        m.grant = Some(mine);
+        assert_eq!(r.send(m), Err(SendError::ForeignGrant));
+        assert_eq!(r.free_grant(mine), Err(GrantError::NotOwner));
+
+        r.set_current(TaskId::new(2));
+        let got = r.recv(ep).unwrap();
+        assert_eq!(got.grant.map(|g| g.va), Some(mine.phys));
+
+        r.set_current(TaskId::new(0));
+        r.free_grant(mine).unwrap();
+        assert_eq!(allocator.freed(), [(mine.phys, 2)]);
+        // Freed frames can't be lent any more.
+        m.grant = Some(mine);
+        assert_eq!(r.send(m), Err(SendError::ForeignGrant));
+        assert_eq!(r.alloc_grant(0), Err(GrantError::InvalidSize));
+        assert_eq!(r.alloc_grant(5), Err(GrantError::OutOfMemory));
+    }
+
+    #[test]
+    fn grants_ending_past_the_address_space_are_foreign() {
+        let mut r = Router::new();
+        r.set_grant_mapper(&IdentityMapper);
+        r.set_frame_allocator(frames(1));
+        let ep = r.create_endpoint(1).unwrap();
+        grant(&mut r, 0, ep, Rights::SEND | Rights::GRANT);
+        r.set_current(TaskId::new(0));
+        r.alloc_grant(1).unwrap();
+
+        let mut m = msg(ep, ep, 1, 1);
+        m.grant = Some(PageGrant::new(!(PAGE_SIZE - 1), 2));
+        assert_eq!(r.send(m), Err(SendError::ForeignGrant));
+    }
+}
+
+
@@ -0,0 +1,414 @@
+//! Interrupts delivered to user-level drivers as IPC.
+//!
+//! A line is bound to a notification (signalled with the binding's bits) or an endpoint
+//! (sent an `IrqEvent`) by a driver holding a capability to the line, which the kernel
+//! hands out at boot like any other. When it fires, the arch IRQ handler calls
+//! `IrqLines::raise`, which masks the line and marks it pending; nothing else is safe
+//! from interrupt context. The scheduler then calls `Router::deliver_irqs`, and the line
+//! stays masked until the driver has handled the device and calls `Router::ack_irq`. A
+//! line whose endpoint or notification has been destroyed is unbound when it next fires,
+//! and stays masked.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use hal::irq::IrqController;
+
+use super::{Message, Router, SendError};
+use crate::cap::{Object, Rights};
+use crate::message::ipc_message;
+
+// Number of interrupt lines that can be bound at once.
+pub const MAX_IRQS: usize = 8;
+
+// Lines are numbered below this; on the GIC, IDs from 1020 up are special or reserved.
+pub const MAX_INTID: u32 = 1020;
+
+ipc_message! {
+    /// Sent by the kernel to an endpoint bound with `bind_irq`.
+    pub struct IrqEvent: 3 {
+        pub irq: u32,
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IrqError {
+    /// Every binding slot is in use.
+    TableFull,
+    AlreadyBound,
+    NotBound,
+    /// Lines can only be bound to endpoints and notifications.
+    InvalidTarget,
+    PermissionDenied,
+    /// No `IrqController` is registered on this platform.
+    Unsupported,
+    /// The line number is `MAX_INTID` or above.
+    InvalidIrq,
+    /// `ack_irq` with no event delivered since the line was last acknowledged.
+    NotRaised,
+}
+
+/// The interrupt handler's side of the IRQ bindings (see `ipc` on handler-side tables).
+pub struct IrqLines {
+    // Bound INTID + 1 per binding slot; 0 for a free slot.
+    lines: [AtomicU32; MAX_IRQS],
+    // Bitmask of binding slots raised since the last `deliver_irqs`.
+    pending: AtomicU32,
+}
+
+impl IrqLines {
+    pub const fn new() -> Self {
+        Self {
+            lines: [const { AtomicU32::new(0) }; MAX_IRQS],
+            pending: AtomicU32::new(0),
+        }
+    }
+
+    /// Called from the arch IRQ handler. If a driver is bound to `irq`, mask it with
+    /// `ctl`, queue it for delivery and return true; otherwise return false and leave the
+    /// line to the caller.
+    pub fn raise(&self, irq: u32, ctl: &dyn IrqController) -> bool {
+        let Some(slot) = self.slot(irq) else {
+            return false;
+        };
+        ctl.mask(irq);
+        self.pending.fetch_or(1 << slot, Ordering::AcqRel);
+        true
+    }
+
+    fn slot(&self, irq: u32) -> Option<usize> {
+        if irq >= MAX_INTID {
+            return None;
+        }
+        self.lines
+            .iter()
+            .position(|l| l.load(Ordering::Acquire) == irq + 1)
+    }
+}
+
+const _: () = assert!(MAX_IRQS <= 32);
+
+#[derive(Copy, Clone)]
+pub(super) struct IrqBinding {
+    irq: u32,
+    target: Object,
+    bits: usize,
+    // An event has been delivered and the line stays masked until it is acknowledged.
+    raised: bool,
+}
+
+impl Router {
+    /// Register the platform's interrupt controller and the table its IRQ handler raises
+    /// lines in. Without one, `bind_irq` reports `Unsupported`.
+    pub fn set_irq_controller(
+        &mut self,
+        ctl: &'static dyn IrqController,
+        lines: &'static IrqLines,
+    ) {
+        self.irq_ctl = Some(ctl);
+        self.irq_lines = Some(lines);
+    }
+
+    /// Deliver interrupt `irq` to `target`: a notification is signalled with `bits`, an
+    /// endpoint is sent an `IrqEvent` (`bits` is ignored). The line is unmasked now and
+    /// after every `ack_irq`.
+    ///
+    /// Needs `Rights::RECV` on both the line (`Object::Irq`) and `target`, so a driver can
+    /// only route its own device's interrupts, and only to itself.
+    pub fn bind_irq(&mut self, irq: u32, target: Object, bits: usize) -> Result<(), IrqError> {
+        let (Some(ctl), Some(lines)) = (self.irq_ctl, self.irq_lines) else {
+            return Err(IrqError::Unsupported);
+        };
+        if irq >= MAX_INTID {
+            return Err(IrqError::InvalidIrq);
+        }
+        if !matches!(target, Object::Endpoint(_) | Object::Notification(_)) {
+            return Err(IrqError::InvalidTarget);
+        }
+        if !self.rights(Object::Irq(irq)).contains(Rights::RECV)
+            || !self.rights(target).contains(Rights::RECV)
+        {
+            return Err(IrqError::PermissionDenied);
+        }
+        if lines.slot(irq).is_some() {
+            return Err(IrqError::AlreadyBound);
+        }
+        let slot = self
+            .irqs
+            .iter()
+            .position(|b| b.is_none())
+            .ok_or(IrqError::TableFull)?;
+        self.irqs[slot] = Some(IrqBinding {
+            irq,
+            target,
+            bits,
+            raised: false,
+        });
+        lines.lines[slot].store(irq + 1, Ordering::Release);
+        ctl.unmask(irq);
+        Ok(())
+    }
+
+    /// Mask `irq` and drop its binding, along with any undelivered event. Needs
+    /// `Rights::RECV` on the line.
+    pub fn unbind_irq(&mut self, irq: u32) -> Result<(), IrqError> {
+        if !self.rights(Object::Irq(irq)).contains(Rights::RECV) {
+            return Err(IrqError::PermissionDenied);
+        }
+        let (slot, _) = self.binding(irq)?;
+        if self.irq_ctl.is_none() || self.irq_lines.is_none() {
+            return Err(IrqError::Unsupported);
+        }
+        self.drop_binding(slot);
+        Ok(())
+    }
+
+    /// Tell the kernel the driver is done with the last event on `irq`, unmasking it.
+    /// Needs `Rights::RECV` on the object the line is bound to. Fails with `NotRaised`,
+    /// leaving the line alone, if no event has been delivered since the last ack.
+    pub fn ack_irq(&mut self, irq: u32) -> Result<(), IrqError> {
+        let (slot, b) = self.binding(irq)?;
+        if !self.rights(b.target).contains(Rights::RECV) {
+            return Err(IrqError::PermissionDenied);
+        }
+        if !b.raised {
+            return Err(IrqError::NotRaised);
+        }
+        if let Some(b) = self.irqs[slot].as_mut() {
+            b.raised = false;
+        }
+        if let Some(ctl) = self.irq_ctl {
+            ctl.unmask(irq);
+        }
+        Ok(())
+    }
+
+    /// Hand raised interrupts to their drivers. Called by the scheduler before it polls.
+    ///
+    /// An event that can't be delivered because the endpoint's mailbox is full stays
+    /// pending (and the line masked) until a later call. One whose target is gone is
+    /// dropped along with the binding, since no driver is left to acknowledge it.
+    pub fn deliver_irqs(&mut self) {
+        let Some(lines) = self.irq_lines else {
+            return;
+        };
+        let pending = lines.pending.swap(0, Ordering::AcqRel);
+        for slot in 0..MAX_IRQS {
+            if pending & (1 << slot) == 0 {
+                continue;
+            }
+            let Some(b) = self.irqs[slot] else {
+                continue;
+            };
+            let r = match b.target {
+                Object::Notification(id) => self.notify(id, b.bits).map_err(SendError::from),
+                Object::Endpoint(ep) => {
+                    self.deliver(Message::encode(ep, ep, 0, &IrqEvent { irq: b.irq }))
+                }
+                // `bind_irq` refuses anything else.
+                Object::Topic(_) | Object::Irq(_) => Ok(()),
+            };
+            match r {
+                Ok(()) => {
+                    if let Some(b) = self.irqs[slot].as_mut() {
+                        b.raised = true;
+                    }
+                }
+                Err(SendError::MailboxFull) => {
+                    lines.pending.fetch_or(1 << slot, Ordering::AcqRel);
+                }
+                Err(_) => self.drop_binding(slot),
+            }
+        }
+    }
+
+    /// Mask the line bound in `slot` and free the slot.
+    fn drop_binding(&mut self, slot: usize) {
+        let Some(b) = self.irqs[slot].take() else {
+            return;
+        };
+        if let Some(ctl) = self.irq_ctl {
+            ctl.mask(b.irq);
+        }
+        if let Some(lines) = self.irq_lines {
+            lines.lines[slot].store(0, Ordering::Release);
+            lines.pending.fetch_and(!(1 << slot), Ordering::AcqRel);
+        }
+    }
+
+    fn binding(&self, irq: u32) -> Result<(usize, IrqBinding), IrqError> {
+        self.irqs
+            .iter()
+            .enumerate()
+            .find_map(|(i, b)| b.filter(|b| b.irq == irq).map(|b| (i, b)))
+            .ok_or(IrqError::NotBound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::ipc::{RecvError, TaskId};
+    use crate::testutil::{grant, leak};
+
+    /// Records which lines are currently masked.
+    #[derive(Default)]
+    struct MockController {
+        masked: RefCell<Vec<u32>>,
+    }
+
+    impl MockController {
+        fn is_masked(&self, irq: u32) -> bool {
+            self.masked.borrow().contains(&irq)
+        }
+    }
+
+    impl IrqController for MockController {
+        fn mask(&self, irq: u32) {
+            self.masked.borrow_mut().push(irq);
+        }
+
+        fn unmask(&self, irq: u32) {
+            self.masked.borrow_mut().retain(|&i| i != irq);
+        }
+    }
+
+    fn setup() -> (Router, &'static MockController, &'static IrqLines) {
+        let ctl = leak(MockController::default());
+        let lines = leak(IrqLines::new());
+        let mut r = Router::new();
+        r.set_irq_controller(ctl, lines);
+        (r, ctl, lines)
+    }
+
+    #[test]
+    fn notification_driver_round_trip() {
+        let (mut r, ctl, lines) = setup();
+        let n = r.create_notification().unwrap();
+        r.bind_irq(40, n.into(), 0b100).unwrap();
+        grant(&mut r, 0, n, Rights::RECV);
+        assert!(!lines.raise(41, ctl));
+        assert!(!lines.raise(u32::MAX, ctl));
+
+        r.set_current(TaskId::new(0));
+        assert_eq!(r.wait(n).unwrap(), 0);
+        assert_eq!(r.ack_irq(40), Err(IrqError::NotRaised));
+        assert!(lines.raise(40, ctl));
+        assert!(ctl.is_masked(40));
+        assert_eq!(r.ack_irq(40), Err(IrqError::NotRaised));
+        assert!(ctl.is_masked(40));
+
+        r.deliver_irqs();
+        assert!(r.is_runnable(TaskId::new(0)));
+        assert_eq!(r.wait(n).unwrap(), 0b100);
+        assert!(ctl.is_masked(40));
+        r.ack_irq(40).unwrap();
+        assert!(!ctl.is_masked(40));
+        assert_eq!(r.ack_irq(40), Err(IrqError::NotRaised));
+    }
+
+    #[test]
+    fn endpoint_gets_irq_event_and_retries_when_full() {
+        let (mut r, ctl, lines) = setup();
+        let ep = r.create_endpoint(1).unwrap();
+        r.bind_irq(33, ep.into(), 0).unwrap();
+        let filler = Message::encode(ep, ep, 0, &IrqEvent { irq: 0 });
+        r.send(filler).unwrap();
+
+        lines.raise(33, ctl);
+        r.deliver_irqs();
+        assert_eq!(
+            r.recv(ep).unwrap().decode::<IrqEvent>(),
+            Some(IrqEvent { irq: 0 })
+        );
+        assert!(matches!(r.recv(ep), Err(RecvError::Empty)));
+
+        r.deliver_irqs();
+        assert_eq!(
+            r.recv(ep).unwrap().decode::<IrqEvent>(),
+            Some(IrqEvent { irq: 33 })
+        );
+    }
+
+    #[test]
+    fn lines_of_destroyed_targets_are_unbound() {
+        let (mut r, ctl, lines) = setup();
+        let ep = r.create_endpoint(1).unwrap();
+        let n = r.create_notification().unwrap();
+        r.bind_irq(33, ep.into(), 0).unwrap();
+        r.bind_irq(34, n.into(), 1).unwrap();
+        r.destroy_endpoint(ep).unwrap();
+        r.destroy_notification(n).unwrap();
+
+        assert!(lines.raise(33, ctl));
+        assert!(lines.raise(34, ctl));
+        r.deliver_irqs();
+        assert!(ctl.is_masked(33) && ctl.is_masked(34));
+        assert_eq!(r.ack_irq(33), Err(IrqError::NotBound));
+        assert_eq!(r.ack_irq(34), Err(IrqError::NotBound));
+        assert!(!lines.raise(33, ctl));
+
+        let ep = r.create_endpoint(1).unwrap();
+        r.bind_irq(33, ep.into(), 0).unwrap();
+        assert!(!ctl.is_masked(33));
+    }
+
+    #[test]
+    fn binding_rules() {
+        let (mut r, _, _) = setup();
+        let ep = r.create_endpoint(1).unwrap();
+        r.bind_irq(5, ep.into(), 0).unwrap();
+        assert_eq!(r.bind_irq(5, ep.into(), 0), Err(IrqError::AlreadyBound));
+        assert_eq!(r.ack_irq(6), Err(IrqError::NotBound));
+        assert_eq!(
+            r.bind_irq(u32::MAX, ep.into(), 0),
+            Err(IrqError::InvalidIrq)
+        );
+        assert_eq!(
+            r.bind_irq(MAX_INTID, ep.into(), 0),
+            Err(IrqError::InvalidIrq)
+        );
+
+        r.set_current(TaskId::new(0));
+        assert_eq!(r.ack_irq(5), Err(IrqError::PermissionDenied));
+        assert_eq!(r.bind_irq(6, ep.into(), 0), Err(IrqError::PermissionDenied));
+
+        assert_eq!(
+            Router::new().bind_irq(5, ep.into(), 0),
+            Err(IrqError::Unsupported)
+        );
+    }
+
+    #[test]
+    fn tasks_bind_lines_they_hold_to_objects_they_receive_from() {
+        let (mut r, ctl, lines) = setup();
+        let ep = r.create_endpoint(1).unwrap();
+        let other = r.create_endpoint(1).unwrap();
+        grant(&mut r, 0, ep, Rights::RECV);
+        grant(&mut r, 0, other, Rights::SEND);
+        grant(&mut r, 0, Object::Irq(7), Rights::RECV);
+
+        r.set_current(TaskId::new(0));
+        assert_eq!(r.bind_irq(8, ep.into(), 0), Err(IrqError::PermissionDenied));
+        assert_eq!(
+            r.bind_irq(7, other.into(), 0),
+            Err(IrqError::PermissionDenied)
+        );
+        assert_eq!(
+            r.bind_irq(7, Object::Irq(7), 0),
+            Err(IrqError::InvalidTarget)
+        );
+        r.bind_irq(7, ep.into(), 0).unwrap();
+        assert!(lines.raise(7, ctl));
+
+        r.set_current(TaskId::new(1));
+        assert_eq!(r.unbind_irq(7), Err(IrqError::PermissionDenied));
+        r.set_current(TaskId::new(0));
+        r.unbind_irq(7).unwrap();
+        assert!(ctl.is_masked(7));
+    }
+}
+
+
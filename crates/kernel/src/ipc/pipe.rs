@@ -0,0 +1,394 @@
+//! Byte-stream pipes.
+//!
+//! A pipe is an endpoint plus a kernel byte buffer. The endpoint names the pipe and
+//! carries the rights to it: `Rights::SEND` on it is the write end and `Rights::RECV`
+//! the read end, so ends are handed to other tasks with `install_cap`. Readers park on
+//! the endpoint while the buffer is empty and writers while it is full, just as they
+//! would for messages.
+//!
+//! The endpoint has no mailbox: plain `send`/`recv` on it fail with `IsPipe`, so bytes
+//! only move through `PipeReader` and `PipeWriter`.
+//!
+//! Once the write end is closed, reads drain what is buffered and then return EOF
+//! (`Ok(Some(0))`). Once the read end is closed, writes fail with `BrokenPipe`. When
+//! both ends are closed the endpoint is destroyed.
+//!
+//! Each end is open or closed as a whole, not per holder: if a write end is handed to
+//! several tasks, the first one to close it closes it for all of them. Pipes are meant
+//! to have one reader and one writer.
+
+use super::{EndpointError, EndpointId, Missing, Router, TaskState};
+use crate::cap::Rights;
+
+// Number of pipes that can be open at once.
+pub const MAX_PIPES: usize = 4;
+
+// Bytes buffered per pipe. Writers block once this much is unread.
+pub const PIPE_BUF: usize = 128;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PipeError {
+    /// The endpoint exists but isn't a pipe, or the ID was never valid.
+    NoSuchPipe,
+    /// The pipe is gone, or the caller already closed this end of it.
+    Closed,
+    PermissionDenied,
+    /// The read end is closed; nobody will ever read what is written.
+    BrokenPipe,
+    /// A read into an empty buffer, whose `Ok(Some(0))` couldn't be told apart from EOF.
+    EmptyBuffer,
+}
+
+impl From<Missing> for PipeError {
+    fn from(m: Missing) -> Self {
+        match m {
+            Missing::NoSuchEndpoint => PipeError::NoSuchPipe,
+            Missing::Closed => PipeError::Closed,
+            // `pipe_slot` resolves the endpoint with `lookup`, which never says this.
+            Missing::Pipe => PipeError::NoSuchPipe,
+        }
+    }
+}
+
+/// The read end of a pipe. Reading needs `Rights::RECV` on its endpoint.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PipeReader(EndpointId);
+
+/// The write end of a pipe. Writing needs `Rights::SEND` on its endpoint.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PipeWriter(EndpointId);
+
+impl PipeReader {
+    /// The read end of the pipe named by `ep`, e.g. one whose cap was installed for us.
+    pub const fn new(ep: EndpointId) -> Self {
+        Self(ep)
+    }
+
+    pub const fn endpoint(self) -> EndpointId {
+        self.0
+    }
+
+    /// Read up to `buf.len()` bytes. Returns `Ok(Some(0))` at EOF; `buf` must not be empty.
+    ///
+    /// If nothing is buffered and the pipe is still open for writing, the current task is
+    /// parked until something is written and `Ok(None)` is returned; retry once it runs.
+    pub fn read(self, ipc: &mut Router, buf: &mut [u8]) -> Result<Option<usize>, PipeError> {
+        ipc.pipe_read(self.0, buf)
+    }
+
+    /// Close the read end. Writers parked on the pipe wake up to `BrokenPipe`.
+    pub fn close(self, ipc: &mut Router) -> Result<(), PipeError> {
+        ipc.close_pipe(self.0, Rights::RECV)
+    }
+}
+
+impl PipeWriter {
+    /// The write end of the pipe named by `ep`, e.g. one whose cap was installed for us.
+    pub const fn new(ep: EndpointId) -> Self {
+        Self(ep)
+    }
+
+    pub const fn endpoint(self) -> EndpointId {
+        self.0
+    }
+
+    /// Write as much of `buf` as fits and return how much that was.
+    ///
+    /// If the buffer is full, the current task is parked until a reader makes room and
+    /// `Ok(None)` is returned; retry once it runs.
+    pub fn write(self, ipc: &mut Router, buf: &[u8]) -> Result<Option<usize>, PipeError> {
+        ipc.pipe_write(self.0, buf)
+    }
+
+    /// Close the write end. Once the buffer is drained, readers see EOF.
+    pub fn close(self, ipc: &mut Router) -> Result<(), PipeError> {
+        ipc.close_pipe(self.0, Rights::SEND)
+    }
+}
+
+#[derive(Copy, Clone)]
+pub(super) struct Pipe {
+    // `None` for a free slot.
+    ep: Option<EndpointId>,
+    buf: [u8; PIPE_BUF],
+    // Index of the oldest unread byte and how many are unread.
+    head: usize,
+    len: usize,
+    reader_open: bool,
+    writer_open: bool,
+}
+
+impl Pipe {
+    pub(super) const fn new() -> Self {
+        Self {
+            ep: None,
+            buf: [0; PIPE_BUF],
+            head: 0,
+            len: 0,
+            reader_open: false,
+            writer_open: false,
+        }
+    }
+}
+
+impl Router {
+    /// Create a pipe. Its creator holds both ends.
+    pub fn create_pipe(&mut self) -> Result<(PipeReader, PipeWriter), EndpointError> {
+        // A slot is also free once its endpoint has been destroyed out from under it.
+        let slot = (0..MAX_PIPES)
+            .find(|&i| self.pipes[i].ep.is_none_or(|ep| !self.is_live(ep)))
+            .ok_or(EndpointError::TableFull)?;
+        let ep = self.create_endpoint(1)?;
+        self.endpoints[ep.slot()].pipe = true;
+        self.pipes[slot] = Pipe {
+            ep: Some(ep),
+            reader_open: true,
+            writer_open: true,
+            ..Pipe::new()
+        };
+        Ok((PipeReader(ep), PipeWriter(ep)))
+    }
+
+    fn pipe_read(&mut self, ep: EndpointId, buf: &mut [u8]) -> Result<Option<usize>, PipeError> {
+        let slot = self.pipe_slot(ep, Rights::RECV)?;
+        let p = &mut self.pipes[slot];
+        if !p.reader_open {
+            return Err(PipeError::Closed);
+        }
+        if buf.is_empty() {
+            return Err(PipeError::EmptyBuffer);
+        }
+        if p.len > 0 {
+            let n = buf.len().min(p.len);
+            for (i, b) in buf[..n].iter_mut().enumerate() {
+                *b = p.buf[(p.head + i) % PIPE_BUF];
+            }
+            p.head = (p.head + n) % PIPE_BUF;
+            p.len -= n;
+            let senders = self
+                .lookup(ep)
+                .map_or(0, |e| core::mem::take(&mut e.senders));
+            self.wake(senders);
+            return Ok(Some(n));
+        }
+        if !p.writer_open {
+            return Ok(Some(0));
+        }
+        let task = self.current_task();
+        if let Ok(e) = self.lookup(ep) {
+            e.waiters |= 1 << task.index();
+        }
+        self.park(task, TaskState::WaitingRecv(ep), None);
+        Ok(None)
+    }
+
+    fn pipe_write(&mut self, ep: EndpointId, buf: &[u8]) -> Result<Option<usize>, PipeError> {
+        let slot = self.pipe_slot(ep, Rights::SEND)?;
+        let p = &mut self.pipes[slot];
+        if !p.writer_open {
+            return Err(PipeError::Closed);
+        }
+        if !p.reader_open {
+            return Err(PipeError::BrokenPipe);
+        }
+        let n = buf.len().min(PIPE_BUF - p.len);
+        if n > 0 || buf.is_empty() {
+            for (i, &b) in buf[..n].iter().enumerate() {
+                p.buf[(p.head + p.len + i) % PIPE_BUF] = b;
+            }
+            p.len += n;
+            let waiters = self
+                .lookup(ep)
+                .map_or(0, |e| core::mem::take(&mut e.waiters));
+            self.wake(waiters);
+            return Ok(Some(n));
+        }
+        let task = self.current_task();
+        if let Ok(e) = self.lookup(ep) {
+            e.senders |= 1 << task.index();
+        }
+        self.park(task, TaskState::WaitingSend(ep), None);
+        Ok(None)
+    }
+
+    /// Close the end of the pipe `ep` that `end` (`SEND` or `RECV`) gives access to.
+    fn close_pipe(&mut self, ep: EndpointId, end: Rights) -> Result<(), PipeError> {
+        let slot = self.pipe_slot(ep, end)?;
+        let p = &mut self.pipes[slot];
+        let open = if end == Rights::SEND {
+            &mut p.writer_open
+        } else {
+            &mut p.reader_open
+        };
+        if !core::mem::replace(open, false) {
+            return Err(PipeError::Closed);
+        }
+        if !p.reader_open && !p.writer_open {
+            *p = Pipe::new();
+            self.retire_endpoint(ep)?;
+            return Ok(());
+        }
+        // Whoever is parked on the other end has something new to see: EOF or EPIPE.
+        let e = self.lookup(ep)?;
+        let parked = core::mem::take(&mut e.waiters) | core::mem::take(&mut e.senders);
+        self.wake(parked);
+        Ok(())
+    }
+
+    fn pipe_slot(&mut self, ep: EndpointId, needs: Rights) -> Result<usize, PipeError> {
+        self.lookup(ep)?;
+        let slot = self
+            .pipes
+            .iter()
+            .position(|p| p.ep == Some(ep))
+            .ok_or(PipeError::NoSuchPipe)?;
+        if !self.rights(ep.into()).contains(needs) {
+            return Err(PipeError::PermissionDenied);
+        }
+        Ok(slot)
+    }
+
+    fn is_live(&self, id: EndpointId) -> bool {
+        self.endpoints[id.slot()]
+            .slot
+            .check(id.generation())
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipc::{OverflowPolicy, RecvError, SendError};
+    use crate::sched::TaskId;
+    use crate::testutil::{grant, msg};
+
+    #[test]
+    fn bytes_flow_in_order_across_wraparound() {
+        let mut r = Router::new();
+        let (rd, wr) = r.create_pipe().unwrap();
+        let mut out = [0u8; 100];
+        for round in 0..5u8 {
+            let data = [round; 100];
+            assert_eq!(wr.write(&mut r, &data), Ok(Some(100)));
+            assert_eq!(rd.read(&mut r, &mut out[..60]), Ok(Some(60)));
+            assert_eq!(rd.read(&mut r, &mut out[60..]), Ok(Some(40)));
+            assert_eq!(out, data);
+        }
+    }
+
+    #[test]
+    fn readers_and_writers_block() {
+        let mut r = Router::new();
+        let (rd, wr) = r.create_pipe().unwrap();
+        grant(&mut r, 0, rd.endpoint(), Rights::RECV);
+        grant(&mut r, 1, wr.endpoint(), Rights::SEND);
+        let (reader, writer) = (TaskId::new(0), TaskId::new(1));
+        let mut buf = [0u8; PIPE_BUF];
+
+        r.set_current(reader);
+        assert_eq!(rd.read(&mut r, &mut buf), Ok(None));
+        assert!(!r.is_runnable(reader));
+
+        r.set_current(writer);
+        assert_eq!(wr.write(&mut r, &[7; PIPE_BUF + 1]), Ok(Some(PIPE_BUF)));
+        assert!(r.is_runnable(reader));
+        assert_eq!(wr.write(&mut r, &[7]), Ok(None));
+        assert!(!r.is_runnable(writer));
+
+        r.set_current(reader);
+        assert_eq!(rd.read(&mut r, &mut buf[..1]), Ok(Some(1)));
+        assert!(r.is_runnable(writer));
+        assert_eq!(r.take_woken(), 0b11);
+    }
+
+    #[test]
+    fn closing_gives_eof_and_broken_pipe() {
+        let mut r = Router::new();
+        let (rd, wr) = r.create_pipe().unwrap();
+        grant(&mut r, 0, rd.endpoint(), Rights::RECV);
+        let mut buf = [0u8; 4];
+
+        wr.write(&mut r, b"hi").unwrap();
+        wr.close(&mut r).unwrap();
+        assert_eq!(wr.write(&mut r, b"!"), Err(PipeError::Closed));
+        r.set_current(TaskId::new(0));
+        assert_eq!(rd.read(&mut r, &mut buf), Ok(Some(2)));
+        assert_eq!(&buf[..2], b"hi");
+        assert_eq!(rd.read(&mut r, &mut buf), Ok(Some(0)));
+        assert_eq!(
+            r.pipe_write(wr.endpoint(), b"x"),
+            Err(PipeError::PermissionDenied)
+        );
+
+        rd.close(&mut r).unwrap();
+        assert_eq!(rd.read(&mut r, &mut buf), Err(PipeError::Closed));
+
+        let mut r = Router::new();
+        let (rd, wr) = r.create_pipe().unwrap();
+        rd.close(&mut r).unwrap();
+        assert_eq!(wr.write(&mut r, b"x"), Err(PipeError::BrokenPipe));
+    }
+
+    #[test]
+    fn closing_wakes_the_other_end() {
+        let mut r = Router::new();
+        let (rd, wr) = r.create_pipe().unwrap();
+        grant(&mut r, 0, rd.endpoint(), Rights::RECV);
+        r.set_current(TaskId::new(0));
+        assert_eq!(rd.read(&mut r, &mut [0u8; 1]), Ok(None));
+
+        r.current = None;
+        wr.close(&mut r).unwrap();
+        assert!(r.is_runnable(TaskId::new(0)));
+        r.set_current(TaskId::new(0));
+        assert_eq!(rd.read(&mut r, &mut [0u8; 1]), Ok(Some(0)));
+    }
+
+    #[test]
+    fn empty_reads_are_not_eof() {
+        let mut r = Router::new();
+        let (rd, wr) = r.create_pipe().unwrap();
+        grant(&mut r, 0, rd.endpoint(), Rights::RECV);
+        r.set_current(TaskId::new(0));
+        assert_eq!(rd.read(&mut r, &mut []), Err(PipeError::EmptyBuffer));
+        assert!(r.is_runnable(TaskId::new(0)));
+
+        r.current = None;
+        wr.write(&mut r, b"x").unwrap();
+        assert_eq!(rd.read(&mut r, &mut []), Err(PipeError::EmptyBuffer));
+        assert_eq!(rd.read(&mut r, &mut [0u8; 1]), Ok(Some(1)));
+    }
+
+    #[test]
+    fn pipe_endpoint_takes_no_messages() {
+        let mut r = Router::new();
+        let (rd, wr) = r.create_pipe().unwrap();
+        let ep = wr.endpoint();
+        assert_eq!(r.send(msg(ep, ep, 1, 0)), Err(SendError::IsPipe));
+        assert!(matches!(r.recv(rd.endpoint()), Err(RecvError::IsPipe)));
+        assert_eq!(r.free_slots(ep), None);
+        let topic = r.create_topic(OverflowPolicy::DropNewest).unwrap();
+        assert_eq!(r.subscribe(topic, ep), Err(EndpointError::IsPipe));
+
+        wr.write(&mut r, b"ok").unwrap();
+        assert_eq!(rd.read(&mut r, &mut [0u8; 2]), Ok(Some(2)));
+    }
+
+    #[test]
+    fn pipe_table_is_reused() {
+        let mut r = Router::new();
+        for _ in 0..MAX_PIPES * 2 {
+            let (rd, wr) = r.create_pipe().unwrap();
+            rd.close(&mut r).unwrap();
+            wr.close(&mut r).unwrap();
+        }
+        let ep = r.create_endpoint(1).unwrap();
+        assert_eq!(
+            PipeReader::new(ep).read(&mut r, &mut [0u8; 1]),
+            Err(PipeError::NoSuchPipe)
+        );
+    }
+}
+
+
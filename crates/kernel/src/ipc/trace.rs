@@ -0,0 +1,146 @@
+//! Fixed-size ring of recent IPC events, for working out why an exchange stalled.
+//!
+//! The router records every message it queues or hands out and every failed operation;
+//! `Router::dump_trace` prints the ring oldest first. Building without the `ipc-trace`
+//! feature replaces the ring with a no-op so it costs nothing.
+
+use core::fmt::Write;
+
+#[cfg(feature = "ipc-trace")]
+use hal::log::LogWriter;
+use hal::log::Logger;
+
+use super::{EndpointId, MsgHeader, MsgTag, RecvError, ReplyError, SendError};
+
+// Number of events kept; older ones are overwritten.
+pub const TRACE_DEPTH: usize = 32;
+
+#[derive(Copy, Clone, Debug)]
+pub enum TraceOp {
+    /// Queued on the destination endpoint (`send`, `call` or one copy of a `publish`).
+    Send,
+    /// Handed to the receiving task.
+    Recv,
+    /// Delivered to a blocked caller.
+    Reply,
+    SendFailed(SendError),
+    RecvFailed(RecvError),
+    ReplyFailed(ReplyError),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct TraceRecord {
+    pub tick: u64,
+    pub op: TraceOp,
+    pub src: EndpointId,
+    pub dst: EndpointId,
+    pub ty: MsgTag,
+    pub seq: u32,
+}
+
+impl TraceRecord {
+    fn write(&self, w: &mut impl Write) -> core::fmt::Result {
+        let op = match self.op {
+            TraceOp::Send => "send",
+            TraceOp::Recv => "recv",
+            TraceOp::Reply => "reply",
+            TraceOp::SendFailed(_) => "send!",
+            TraceOp::RecvFailed(_) => "recv!",
+            TraceOp::ReplyFailed(_) => "reply!",
+        };
+        write!(
+            w,
+            "[{:>6}] {:<6} {:#06x} -> {:#06x} ty={} seq={}",
+            self.tick,
+            op,
+            self.src.raw(),
+            self.dst.raw(),
+            self.ty.0,
+            self.seq,
+        )?;
+        match self.op {
+            TraceOp::SendFailed(e) => write!(w, " ({:?})", e)?,
+            TraceOp::RecvFailed(e) => write!(w, " ({:?})", e)?,
+            TraceOp::ReplyFailed(e) => write!(w, " ({:?})", e)?,
+            _ => {}
+        }
+        w.write_str("\n")
+    }
+}
+
+#[cfg(feature = "ipc-trace")]
+#[derive(Copy, Clone)]
+pub struct Trace {
+    ring: [Option<TraceRecord>; TRACE_DEPTH],
+    // Total events ever recorded; the next one goes to `next % TRACE_DEPTH`.
+    next: usize,
+}
+
+#[cfg(feature = "ipc-trace")]
+impl Trace {
+    pub const fn new() -> Self {
+        Self {
+            ring: [None; TRACE_DEPTH],
+            next: 0,
+        }
+    }
+
+    pub fn record(&mut self, tick: u64, op: TraceOp, header: &MsgHeader) {
+        self.ring[self.next % TRACE_DEPTH] = Some(TraceRecord {
+            tick,
+            op,
+            src: header.src,
+            dst: header.dst,
+            ty: header.ty,
+            seq: header.seq,
+        });
+        self.next = self.next.wrapping_add(1);
+    }
+
+    /// Record an operation on `dst` that failed before there was a message to describe.
+    pub fn record_failure(&mut self, tick: u64, op: TraceOp, dst: EndpointId) {
+        let header = MsgHeader {
+            src: EndpointId::from_raw(0),
+            dst,
+            ty: MsgTag(0),
+            len: 0,
+            prio: 0,
+            seq: 0,
+        };
+        self.record(tick, op, &header);
+    }
+
+    pub fn dump(&self, logger: &dyn Logger) {
+        let mut w = LogWriter(logger);
+        let shown = self.next.min(TRACE_DEPTH);
+        let _ = writeln!(w, "ipc trace: {} events, last {}:", self.next, shown);
+        for i in self.next - shown..self.next {
+            if let Some(r) = &self.ring[i % TRACE_DEPTH] {
+                let _ = r.write(&mut w);
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "ipc-trace"))]
+#[derive(Copy, Clone)]
+pub struct Trace;
+
+#[cfg(not(feature = "ipc-trace"))]
+impl Trace {
+    pub const fn new() -> Self {
+        Self
+    }
+
+    #[inline(always)]
+    pub fn record(&mut self, _tick: u64, _op: TraceOp, _header: &MsgHeader) {}
+
+    #[inline(always)]
+    pub fn record_failure(&mut self, _tick: u64, _op: TraceOp, _dst: EndpointId) {}
+
+    pub fn dump(&self, logger: &dyn Logger) {
+        logger.log("ipc trace: disabled (built without the `ipc-trace` feature)\n");
+    }
+}
+
+
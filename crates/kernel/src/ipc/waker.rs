@@ -0,0 +1,172 @@
+//! `core::task::Waker`s for tasks written as futures (see `executor`).
+//!
+//! A future that waits on the router parks its task exactly like the blocking calls do,
+//! and hands the router its `cx.waker()` with `set_waker`. Whatever would wake the parked
+//! task (a message arriving, a reply, a deadline passing) then wakes that waker instead.
+//! A future that waits on some other event source stores `cx.waker()` and wakes it from
+//! there. Either way, waking sets the task's flag in `WakeFlags`, and `take_woken`
+//! reports it along with the tasks the router woke directly.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{RawWaker, RawWakerVTable, Waker};
+
+use super::{Router, TaskState};
+use crate::sched::{TaskId, MAX_TASKS};
+
+/// The wakers' side of task wakeups. As a handler-side table (see `ipc`), it lets a waker
+/// be woken from anywhere, interrupt context included.
+pub struct WakeFlags {
+    flags: [AtomicBool; MAX_TASKS],
+}
+
+impl WakeFlags {
+    pub const fn new() -> Self {
+        Self {
+            flags: [const { AtomicBool::new(false) }; MAX_TASKS],
+        }
+    }
+
+    /// A waker that marks `task` for polling.
+    pub fn waker(&'static self, task: TaskId) -> Waker {
+        let flag: &'static AtomicBool = &self.flags[task.index()];
+        // SAFETY: the data pointer is a `&'static AtomicBool`, which is all `VTABLE`
+        // expects, and it is valid from any thread.
+        unsafe { Waker::from_raw(RawWaker::new(flag as *const _ as *const (), &VTABLE)) }
+    }
+
+    /// Drain the tasks woken since the last call, as a bitmask by task index.
+    fn take(&self) -> u32 {
+        self.flags
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.swap(false, Ordering::AcqRel))
+            .fold(0, |mask, (i, _)| mask | 1 << i)
+    }
+}
+
+const VTABLE: RawWakerVTable = RawWakerVTable::new(clone_waker, wake_flag, wake_flag, drop_waker);
+
+unsafe fn clone_waker(data: *const ()) -> RawWaker {
+    RawWaker::new(data, &VTABLE)
+}
+
+unsafe fn wake_flag(data: *const ()) {
+    // SAFETY: `data` came from `WakeFlags::waker`.
+    let flag = unsafe { &*(data as *const AtomicBool) };
+    flag.store(true, Ordering::Release);
+}
+
+unsafe fn drop_waker(_data: *const ()) {}
+
+impl Router {
+    /// Register the flags wakers set (see `WakeFlags::waker`).
+    pub fn set_wake_flags(&mut self, flags: &'static WakeFlags) {
+        self.wake_flags = Some(flags);
+    }
+
+    /// A waker for the task being polled. Without registered `WakeFlags` it does
+    /// nothing, and a task parked with it is only polled again on the next tick.
+    pub fn current_waker(&self) -> Waker {
+        match (self.wake_flags, self.current) {
+            (Some(flags), Some(task)) => flags.waker(task),
+            _ => Waker::noop().clone(),
+        }
+    }
+
+    /// Wake `waker` rather than report the current task in `take_woken` when the task is
+    /// next woken from a park.
+    pub fn set_waker(&mut self, waker: &Waker) {
+        let task = self.current_task();
+        match &mut self.wakers[task.index()] {
+            Some(w) => w.clone_from(waker),
+            slot => *slot = Some(waker.clone()),
+        }
+    }
+
+    /// Park the current task until tick `deadline` (see `now`). Returns true, without
+    /// parking, once the deadline has passed; until then it should be called again
+    /// whenever the task runs.
+    pub fn sleep_until(&mut self, deadline: u64) -> bool {
+        if self.now() >= deadline {
+            return true;
+        }
+        let task = self.current_task();
+        self.park(task, TaskState::Sleeping, Some(deadline));
+        false
+    }
+
+    /// Tasks whose wakers were woken since the last call.
+    pub(super) fn take_waker_wakes(&self) -> u32 {
+        self.wake_flags.map_or(0, |f| f.take())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    use super::*;
+    use crate::cap::Rights;
+    use crate::testutil::{grant, leak, msg};
+
+    /// Counts its wakeups.
+    struct Count(AtomicUsize);
+
+    impl Wake for Count {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn wakers_and_deadlines_wake_tasks() {
+        let flags = leak(WakeFlags::new());
+        let mut r = Router::new();
+        r.set_wake_flags(flags);
+
+        r.set_current(TaskId::new(2));
+        let waker = r.current_waker();
+        let other = waker.clone();
+        waker.wake_by_ref();
+        other.wake();
+        assert_eq!(r.take_woken(), 1 << 2);
+        assert_eq!(r.take_woken(), 0);
+
+        assert!(!r.sleep_until(2));
+        assert!(!r.is_runnable(TaskId::new(2)));
+        r.advance_tick();
+        assert!(!r.is_runnable(TaskId::new(2)));
+        r.advance_tick();
+        assert!(r.is_runnable(TaskId::new(2)));
+        assert_eq!(r.take_woken(), 1 << 2);
+        assert!(r.sleep_until(2));
+    }
+
+    #[test]
+    fn parked_futures_are_woken_through_their_waker() {
+        let mut r = Router::new();
+        let ep = r.create_endpoint(1).unwrap();
+        grant(&mut r, 0, ep, Rights::RECV);
+        let count = Arc::new(Count(AtomicUsize::new(0)));
+        let waker = Waker::from(count.clone());
+
+        r.set_current(TaskId::new(0));
+        assert!(matches!(r.recv_blocking(ep), Ok(None)));
+        r.set_waker(&waker);
+        r.current = None;
+        r.send(msg(ep, ep, 1, 1)).unwrap();
+        assert_eq!(count.0.load(Ordering::Relaxed), 1);
+        assert_eq!(r.take_woken(), 0);
+
+        r.set_current(TaskId::new(0));
+        assert!(!r.sleep_until(1));
+        r.set_waker(&waker);
+        r.advance_tick();
+        assert_eq!(count.0.load(Ordering::Relaxed), 2);
+        assert!(r.is_runnable(TaskId::new(0)));
+    }
+}
+
+
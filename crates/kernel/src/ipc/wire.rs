@@ -0,0 +1,222 @@
+//! Versioned binary layout for exchanging messages outside the kernel.
+//!
+//! `Message` and `MsgHeader` are in-kernel types whose layout may change with any build.
+//! Anything that crosses a build boundary (user programs, host tools, logs captured off
+//! the target) uses `WireMessage` instead: `WIRE_SIZE` bytes with no padding and every
+//! field little-endian, laid out as follows.
+//!
+//! ```text
+//! offset  size  field
+//!      0     1  magic     WIRE_MAGIC
+//!      1     1  version   WIRE_VERSION
+//!      2     2  src       EndpointId
+//!      4     2  dst       EndpointId
+//!      6     2  ty        MsgTag
+//!      8     1  len       payload bytes in use, at most MAX_PAYLOAD
+//!      9     1  prio
+//!     10     2  reserved  must be zero
+//!     12     4  seq
+//!     16     4  badge     stamped by the kernel
+//!     20    16  payload
+//! ```
+//!
+//! Reply caps, capabilities and page grants are kernel objects and never appear on the
+//! wire. Changing anything in this table means bumping `WIRE_VERSION`; the assertions
+//! below keep it from changing by accident.
+
+use core::mem::{offset_of, size_of};
+
+use super::{EndpointId, Message, MsgHeader, MsgTag, MAX_PAYLOAD};
+use crate::cap::Badge;
+
+/// First byte of every wire message.
+pub const WIRE_MAGIC: u8 = 0xB5;
+
+/// Layout version this kernel reads and writes.
+pub const WIRE_VERSION: u8 = 1;
+
+pub const WIRE_SIZE: usize = 36;
+
+// Version 1 carries exactly 16 payload bytes; a different `MAX_PAYLOAD` is a new version.
+const WIRE_PAYLOAD: usize = 16;
+const _: () = assert!(MAX_PAYLOAD == WIRE_PAYLOAD);
+
+/// A message in wire form. Every field is a byte array, so the struct has alignment 1
+/// and no padding, and can be viewed as plain bytes (see `as_bytes`/`from_bytes`).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct WireMessage {
+    magic: u8,
+    version: u8,
+    src: [u8; 2],
+    dst: [u8; 2],
+    ty: [u8; 2],
+    len: u8,
+    prio: u8,
+    reserved: [u8; 2],
+    seq: [u8; 4],
+    badge: [u8; 4],
+    payload: [u8; WIRE_PAYLOAD],
+}
+
+const _: () = {
+    assert!(size_of::<WireMessage>() == WIRE_SIZE);
+    assert!(core::mem::align_of::<WireMessage>() == 1);
+    assert!(offset_of!(WireMessage, magic) == 0);
+    assert!(offset_of!(WireMessage, version) == 1);
+    assert!(offset_of!(WireMessage, src) == 2);
+    assert!(offset_of!(WireMessage, dst) == 4);
+    assert!(offset_of!(WireMessage, ty) == 6);
+    assert!(offset_of!(WireMessage, len) == 8);
+    assert!(offset_of!(WireMessage, prio) == 9);
+    assert!(offset_of!(WireMessage, reserved) == 10);
+    assert!(offset_of!(WireMessage, seq) == 12);
+    assert!(offset_of!(WireMessage, badge) == 16);
+    assert!(offset_of!(WireMessage, payload) == 20);
+};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WireError {
+    /// Too short, or doesn't start with `WIRE_MAGIC`.
+    BadMagic,
+    /// Written for a layout version this kernel doesn't know.
+    UnsupportedVersion(u8),
+    /// `len` is larger than `MAX_PAYLOAD`.
+    PayloadTooLarge,
+    /// A reserved field is not zero.
+    Malformed,
+}
+
+impl WireMessage {
+    pub fn as_bytes(&self) -> &[u8; WIRE_SIZE] {
+        // SAFETY: `WireMessage` is `repr(C)` with only byte fields, so it is exactly
+        // `WIRE_SIZE` initialized bytes with alignment 1 (checked above).
+        unsafe { &*(self as *const Self as *const [u8; WIRE_SIZE]) }
+    }
+
+    /// View the start of `buf` as a wire message, checking magic and version.
+    pub fn from_bytes(buf: &[u8]) -> Result<&Self, WireError> {
+        let bytes: &[u8; WIRE_SIZE] = buf
+            .get(..WIRE_SIZE)
+            .and_then(|b| b.try_into().ok())
+            .ok_or(WireError::BadMagic)?;
+        // SAFETY: as in `as_bytes`; any byte pattern is a valid `WireMessage`.
+        let wire = unsafe { &*(bytes as *const [u8; WIRE_SIZE] as *const Self) };
+        if wire.magic != WIRE_MAGIC {
+            return Err(WireError::BadMagic);
+        }
+        if wire.version != WIRE_VERSION {
+            return Err(WireError::UnsupportedVersion(wire.version));
+        }
+        Ok(wire)
+    }
+}
+
+impl Message {
+    /// Encode the header, badge and payload in wire form. Any reply cap, capability or
+    /// grant is left behind.
+    pub fn to_wire(self) -> WireMessage {
+        let h = &self.header;
+        WireMessage {
+            magic: WIRE_MAGIC,
+            version: WIRE_VERSION,
+            src: h.src.raw().to_le_bytes(),
+            dst: h.dst.raw().to_le_bytes(),
+            ty: h.ty.0.to_le_bytes(),
+            len: h.len,
+            prio: h.prio,
+            reserved: [0; 2],
+            seq: h.seq.to_le_bytes(),
+            badge: self.badge.0.to_le_bytes(),
+            payload: self.payload,
+        }
+    }
+
+    /// Decode a wire message. The badge is carried over as written; when a message comes
+    /// in from outside, sending it through the router stamps the real one.
+    pub fn from_wire(wire: &WireMessage) -> Result<Message, WireError> {
+        if wire.magic != WIRE_MAGIC {
+            return Err(WireError::BadMagic);
+        }
+        if wire.version != WIRE_VERSION {
+            return Err(WireError::UnsupportedVersion(wire.version));
+        }
+        if wire.reserved != [0; 2] {
+            return Err(WireError::Malformed);
+        }
+        if wire.len as usize > MAX_PAYLOAD {
+            return Err(WireError::PayloadTooLarge);
+        }
+        let header = MsgHeader {
+            src: EndpointId::from_raw(u16::from_le_bytes(wire.src)),
+            dst: EndpointId::from_raw(u16::from_le_bytes(wire.dst)),
+            ty: MsgTag(u16::from_le_bytes(wire.ty)),
+            len: wire.len,
+            prio: wire.prio,
+            seq: u32::from_le_bytes(wire.seq),
+        };
+        let mut msg = Message::new(header, wire.payload);
+        msg.badge = Badge(u32::from_le_bytes(wire.badge));
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::msg;
+
+    #[test]
+    fn layout_is_little_endian_and_fixed() {
+        let (src, dst) = (EndpointId::from_raw(0x0102), EndpointId::from_raw(0x0304));
+        let mut m = msg(src, dst, 0x0506, 0x0708_090A).with_prio(7);
+        m.header.len = 2;
+        m.payload[..2].copy_from_slice(&[0xEE, 0xFF]);
+        m.badge = Badge(0x1122_3344);
+
+        let mut expected = [0u8; WIRE_SIZE];
+        expected[..20].copy_from_slice(&[
+            WIRE_MAGIC, 1, 0x02, 0x01, 0x04, 0x03, 0x06, 0x05, 2, 7, 0, 0, 0x0A, 0x09, 0x08, 0x07,
+            0x44, 0x33, 0x22, 0x11,
+        ]);
+        expected[20..22].copy_from_slice(&[0xEE, 0xFF]);
+        assert_eq!(m.to_wire().as_bytes(), &expected);
+
+        let back = Message::from_wire(WireMessage::from_bytes(&expected).unwrap()).unwrap();
+        assert_eq!(back.to_wire(), m.to_wire());
+    }
+
+    #[test]
+    fn rejects_foreign_and_malformed_input() {
+        let ep = EndpointId::from_raw(1);
+        let good = *msg(ep, ep, 3, 4).to_wire().as_bytes();
+        assert_eq!(
+            WireMessage::from_bytes(&good[..WIRE_SIZE - 1]),
+            Err(WireError::BadMagic)
+        );
+
+        let mut b = good;
+        b[0] ^= 0xFF;
+        assert_eq!(WireMessage::from_bytes(&b), Err(WireError::BadMagic));
+        let mut b = good;
+        b[1] = 2;
+        assert_eq!(
+            WireMessage::from_bytes(&b),
+            Err(WireError::UnsupportedVersion(2))
+        );
+
+        let mut b = good;
+        b[8] = MAX_PAYLOAD as u8 + 1;
+        let wire = WireMessage::from_bytes(&b).unwrap();
+        assert_eq!(
+            Message::from_wire(wire).err(),
+            Some(WireError::PayloadTooLarge)
+        );
+        let mut b = good;
+        b[10] = 1;
+        let wire = WireMessage::from_bytes(&b).unwrap();
+        assert_eq!(Message::from_wire(wire).err(), Some(WireError::Malformed));
+    }
+}
+
+
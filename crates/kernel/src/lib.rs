@@ -1,11 +1,26 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
+use cap::{Badge, Capability, Rights};
 use hal::log::Logger;
+use sched::Task;
 
+pub use ipc::{FaultAccess, FaultInfo};
+pub use sched::MAX_TASKS;
+pub use thread::{ThreadError, ThreadId};
+
+mod cap;
+mod executor;
 mod ipc;
+mod message;
 mod sched;
+pub mod spsc;
+#[cfg(test)]
+mod testutil;
+mod thread;
 
 use core::cell::UnsafeCell;
+use core::pin::pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 #[repr(transparent)]
 struct RouterCell(UnsafeCell<ipc::Router>);
@@ -14,18 +29,307 @@ unsafe impl Sync for RouterCell {}
 // Force the router into a writable section. On some bare-metal targets, a `static`
 // with interior mutability can otherwise end up in a read-only segment, causing
 // a data abort when we first write to it (exactly what we saw on aarch64 QEMU virt).
+//
+// The scheduler holds this as `&mut` for as long as it runs, so interrupt handlers must
+// never touch it; they hand data over through `spsc` queues or the router's handler-side
+// tables below (see `ipc`).
 #[link_section = ".data"]
 static ROUTER: RouterCell = RouterCell(UnsafeCell::new(ipc::Router::new()));
 
+/// Register the arch hook that maps IPC page grants into tasks. Call before `kmain`;
+/// platforms that never do so simply refuse grants.
+pub fn set_grant_mapper(mapper: &'static dyn hal::mem::GrantMapper) {
+    let router: &mut ipc::Router = unsafe { &mut *ROUTER.0.get() };
+    router.set_grant_mapper(mapper);
+}
+
+/// Register the platform's tick counter as the time base for IPC deadlines and thread
+/// sleeps. Call before `kmain` and `start_threads`; without one, the schedulers count
+/// their own ticks.
+pub fn set_tick_source(ticks: fn() -> u64) {
+    let router: &mut ipc::Router = unsafe { &mut *ROUTER.0.get() };
+    router.set_clock(ticks);
+    let threads: &mut thread::Threads = unsafe { &mut *THREADS.0.get() };
+    threads.set_clock(ticks);
+}
+
+static IRQ_LINES: ipc::IrqLines = ipc::IrqLines::new();
+
+/// Register the interrupt controller used to mask lines bound to drivers. Call before
+/// `kmain`; platforms that never do so can't deliver interrupts as IPC.
+pub fn set_irq_controller(ctl: &'static dyn hal::irq::IrqController) {
+    let router: &mut ipc::Router = unsafe { &mut *ROUTER.0.get() };
+    router.set_irq_controller(ctl, &IRQ_LINES);
+}
+
+/// Called by the arch IRQ handler for interrupts it doesn't handle itself. If a driver
+/// is bound to `irq`, masks it, queues it for delivery and returns true.
+pub fn handle_irq(irq: u32, ctl: &dyn hal::irq::IrqController) -> bool {
+    IRQ_LINES.raise(irq, ctl)
+}
+
+static FAULTS: ipc::FaultSlots = ipc::FaultSlots::new();
+
+static WAKE_FLAGS: ipc::WakeFlags = ipc::WakeFlags::new();
+
+#[repr(transparent)]
+struct ThreadsCell(UnsafeCell<thread::Threads>);
+unsafe impl Sync for ThreadsCell {}
+
+// Only touched while setting up threads before `start_threads`, and afterwards from the
+// arch's timer interrupt and exception handlers, which never nest, or from a thread with
+// interrupts masked.
+#[link_section = ".data"]
+static THREADS: ThreadsCell = ThreadsCell(UnsafeCell::new(thread::Threads::new()));
+
+// Where a faulted thread's pager has got to.
+fn fault_status(task: sched::TaskId) -> ipc::FaultStatus {
+    FAULTS.status(task)
+}
+
+// `kmain`'s own tasks take the first task IDs; threads handed to `supervise_thread` act
+// as the rest, one each.
+const THREAD_TASKS: core::ops::Range<usize> = 3..sched::MAX_TASKS;
+
+// Next task ID `supervise_thread` hands out. IDs aren't reused, so a new thread never
+// inherits a dead one's IPC state.
+static NEXT_THREAD_TASK: AtomicUsize = AtomicUsize::new(THREAD_TASKS.start);
+
+/// Register the arch hooks that build and start thread contexts. Call before
+/// `spawn_thread`.
+pub fn set_thread_arch(arch: &'static dyn hal::thread::ThreadArch) {
+    let threads: &mut thread::Threads = unsafe { &mut *THREADS.0.get() };
+    threads.set_arch(arch);
+}
+
+/// Register where thread stacks and the frames tasks lend with page grants come from.
+/// Call before `spawn_thread` and `kmain`.
+pub fn set_frame_allocator(frames: &'static dyn hal::mem::FrameAllocator) {
+    let router: &mut ipc::Router = unsafe { &mut *ROUTER.0.get() };
+    router.set_frame_allocator(frames);
+    let threads: &mut thread::Threads = unsafe { &mut *THREADS.0.get() };
+    threads.set_frame_allocator(frames);
+}
+
+/// Let threads of `priority` run `ticks` timer ticks before others of the same priority
+/// get a turn.
+pub fn set_thread_time_slice(priority: u8, ticks: u32) -> Result<(), ThreadError> {
+    hal::arch::without_interrupts(|| {
+        let threads: &mut thread::Threads = unsafe { &mut *THREADS.0.get() };
+        threads.set_time_slice(priority, ticks)
+    })
+}
+
+/// Create a thread that runs `entry(arg)` on a stack of at least `stack_size` bytes.
+/// Callable before `start_threads` and from any running thread; a caller of lower
+/// priority than the new thread is preempted before this returns.
+pub fn spawn_thread(
+    entry: hal::thread::ThreadEntry,
+    arg: usize,
+    stack_size: usize,
+    priority: u8,
+) -> Result<ThreadId, ThreadError> {
+    let (id, preempt) = hal::arch::without_interrupts(|| {
+        let threads: &mut thread::Threads = unsafe { &mut *THREADS.0.get() };
+        let id = threads.spawn(entry, arg, stack_size, priority)?;
+        Ok((id, threads.should_preempt()))
+    })?;
+    if preempt {
+        yield_thread();
+    }
+    Ok(id)
+}
+
+/// Give `thread` an IPC identity of its own, paged by the pager task `kmain` runs: a
+/// fault it takes is reported there rather than killing the thread on the spot. Call
+/// before `kmain`.
+///
+/// Each call uses up one of the `MAX_TASKS - 3` task IDs set aside for threads, for
+/// good: the router's state for a task is never reset, so the ID of a reaped thread is
+/// not handed out again. Once they are gone this fails with `ThreadError::TableFull`.
+pub fn supervise_thread(thread: ThreadId) -> Result<(), ThreadError> {
+    hal::arch::without_interrupts(|| {
+        let threads: &mut thread::Threads = unsafe { &mut *THREADS.0.get() };
+        let index = NEXT_THREAD_TASK.fetch_add(1, Ordering::Relaxed);
+        if !THREAD_TASKS.contains(&index) {
+            return Err(ThreadError::TableFull);
+        }
+        threads.set_task(thread, sched::TaskId::new(index))
+    })
+}
+
+/// End the calling thread. Its stack is freed once another thread is running.
+pub fn exit_thread() -> ! {
+    let arch = hal::arch::without_interrupts(|| {
+        let threads: &mut thread::Threads = unsafe { &mut *THREADS.0.get() };
+        threads.exit_current();
+        threads.arch()
+    });
+    // Only comes back while there is nothing else to run.
+    loop {
+        match arch {
+            Some(arch) => arch.yield_now(),
+            None => core::hint::spin_loop(),
+        }
+    }
+}
+
+/// Give up the rest of the calling thread's time slice.
+pub fn yield_thread() {
+    let threads: &thread::Threads = unsafe { &*THREADS.0.get() };
+    if let Some(arch) = threads.arch() {
+        arch.yield_now();
+    }
+}
+
+/// Block the calling thread until tick `deadline` (see `set_tick_source`). Returns at
+/// once if it has already passed.
+pub fn sleep_until(deadline: u64) {
+    let asleep = hal::arch::without_interrupts(|| {
+        let threads: &mut thread::Threads = unsafe { &mut *THREADS.0.get() };
+        threads.sleep_current(deadline)
+    });
+    if !asleep {
+        return;
+    }
+    yield_thread();
+    // Nothing else was ready: the next tick that wakes a thread switches away from here.
+    while hal::arch::without_interrupts(|| {
+        let threads: &thread::Threads = unsafe { &*THREADS.0.get() };
+        threads.current_sleeping()
+    }) {
+        hal::arch::halt();
+    }
+}
+
+/// Block the calling thread for `ticks` ticks.
+pub fn sleep_ticks(ticks: u64) {
+    let now = hal::arch::without_interrupts(|| {
+        let threads: &thread::Threads = unsafe { &*THREADS.0.get() };
+        threads.now()
+    });
+    sleep_until(now.saturating_add(ticks));
+}
+
+/// Switch to the first thread; from here on the timer tick schedules them.
+pub fn start_threads() -> ! {
+    let threads: &mut thread::Threads = unsafe { &mut *THREADS.0.get() };
+    threads.start();
+    panic!("thread: nothing to run");
+}
+
+/// Called by the arch timer interrupt handler with the interrupted context. Returns the
+/// context to resume, which is `current` unless it is time for another thread to run.
+pub fn preempt_tick(current: usize) -> usize {
+    let threads: &mut thread::Threads = unsafe { &mut *THREADS.0.get() };
+    threads.tick(fault_status).map_or(current, |ctx| ctx.0)
+}
+
+/// Called by the arch exception handler when the running thread traps in through
+/// `ThreadArch::yield_now`. Returns the context to resume, as `preempt_tick` does.
+pub fn thread_yield(current: usize) -> usize {
+    let threads: &mut thread::Threads = unsafe { &mut *THREADS.0.get() };
+    threads
+        .yield_current(fault_status)
+        .map_or(current, |ctx| ctx.0)
+}
+
+// Registered with the router: a thread its pager just resumed preempts the scheduler
+// thread there and then if it has the higher priority.
+fn resume_thread() {
+    let preempt = hal::arch::without_interrupts(|| {
+        let threads: &mut thread::Threads = unsafe { &mut *THREADS.0.get() };
+        threads.wake_resumed(fault_status)
+    });
+    if preempt {
+        yield_thread();
+    }
+}
+
+/// Called by the arch exception handler when the running thread takes a synchronous
+/// abort. A thread handed to `supervise_thread` stays off the CPU until its pager, told
+/// about the fault once the task scheduler next runs, resumes it; it is reaped if the
+/// pager kills it. Returns the context to run instead, or `None` if the fault is fatal:
+/// it didn't come from a supervised thread, or nothing else can run. The arch handler
+/// then reports the fault and halts.
+pub fn thread_fault(fault: FaultInfo) -> Option<usize> {
+    let threads: &mut thread::Threads = unsafe { &mut *THREADS.0.get() };
+    let cur = threads.current()?;
+    let task = threads.get(cur)?.task?;
+    FAULTS.raise(task, fault);
+    threads.suspend_current(fault_status).map(|ctx| ctx.0)
+}
+
+/// Print the recent IPC history (see `ipc::trace`) through `logger`.
+pub fn dump_ipc_trace(logger: &dyn Logger) {
+    let router: &ipc::Router = unsafe { &*ROUTER.0.get() };
+    router.dump_trace(logger);
+}
+
 pub fn kmain(logger: &dyn Logger) -> ! {
     logger.log("rustOS: kernel online\n");
     logger.log("rustOS: microkernel step 1 (IPC + cooperative scheduling)\n");
 
     let router: &mut ipc::Router = unsafe { &mut *ROUTER.0.get() };
+    router.set_fault_slots(&FAULTS);
+    router.set_resume_hook(resume_thread);
+    router.set_wake_flags(&WAKE_FLAGS);
+
+    // The table is empty at boot, so these can only fail if MAX_ENDPOINTS is zero.
+    // Pong gets a deeper mailbox since it is the side that may see bursts.
+    let ping_ep = router
+        .create_endpoint(1)
+        .expect("ipc: no endpoint for ping");
+    let pong_ep = router
+        .create_endpoint(4)
+        .expect("ipc: no endpoint for pong");
+    let pager_ep = router
+        .create_endpoint(THREAD_TASKS.len())
+        .expect("ipc: no endpoint for the pager");
+
+    // Give each task only what it needs: ping owns its mailbox and may call pong, pong
+    // and the pager only receive on their own endpoints (replies go through one-shot
+    // reply caps).
+    // Task IDs are positions in `tasks` below. Badges let pong tell its clients apart
+    // and let ping check that replies really come from pong.
+    let (ping_task, pong_task) = (sched::TaskId::new(0), sched::TaskId::new(1));
+    let pager_task = sched::TaskId::new(2);
+    let (ping_badge, pong_badge) = (Badge(1), Badge(2));
+    let grants = [
+        (ping_task, ping_ep, Rights::RECV, Badge::NONE),
+        (ping_task, pong_ep, Rights::SEND, ping_badge),
+        (pong_task, pong_ep, Rights::RECV, pong_badge),
+        (pager_task, pager_ep, Rights::RECV, Badge::NONE),
+    ];
+    for (task, ep, rights, badge) in grants {
+        router
+            .install_cap(
+                task,
+                Capability {
+                    obj: ep.into(),
+                    rights,
+                    badge,
+                },
+            )
+            .expect("ipc: cap table full at boot");
+    }
+
+    let ping_io = executor::Io::new(ping_ep);
+    let ping_fut = pin!(sched::ping(&ping_io, logger, pong_ep, pong_badge));
+    let mut ping = executor::AsyncTask::new(&ping_io, ping_fut);
+    let mut pong = sched::PongTask::new(pong_ep);
+    let pager_io = executor::Io::new(pager_ep);
+    let pager_fut = pin!(sched::pager(&pager_io, logger));
+    let mut pager = executor::AsyncTask::new(&pager_io, pager_fut);
+    let mut tasks: [&mut dyn Task; THREAD_TASKS.start] = [&mut ping, &mut pong, &mut pager];
 
-    let mut ping = sched::PingTask::new();
-    let mut pong = sched::PongTask::new();
-    let mut tasks: [&mut dyn sched::Task; 2] = [&mut ping, &mut pong];
+    // Every task a supervised thread can act as is paged by `pager`, including ones no
+    // thread has been given yet.
+    for i in THREAD_TASKS {
+        router
+            .set_pager(sched::TaskId::new(i), pager_ep)
+            .expect("ipc: pager endpoint unusable");
+    }
 
     sched::run(&mut tasks, logger, router)
 }
@@ -0,0 +1,310 @@
+//! Typed IPC messages.
+//!
+//! Instead of packing payload bytes by hand, declare a message type with `ipc_message!`
+//! and use `Message::encode`/`Message::decode`. Every type gets a stable `MsgTag` that is
+//! written to `MsgHeader::ty`, so receivers can tell message types apart.
+//!
+//! Tags are part of the wire protocol: never renumber or reuse one.
+
+use crate::ipc::{EndpointId, Message, MsgHeader, MsgTag, MAX_PAYLOAD, PRIO_NORMAL};
+
+/// A field type that can appear in an `ipc_message!` struct.
+///
+/// Values are encoded as exactly `SIZE` little-endian bytes.
+pub trait Wire: Sized {
+    const SIZE: usize;
+
+    /// Write `self` to the start of `buf` (at least `SIZE` bytes long).
+    fn put(&self, buf: &mut [u8]);
+
+    /// Read a value from the start of `buf`; `None` if it is too short or malformed.
+    fn get(buf: &[u8]) -> Option<Self>;
+}
+
+macro_rules! impl_wire_int {
+    ($($ty:ty),*) => {
+        $(
+            impl Wire for $ty {
+                const SIZE: usize = core::mem::size_of::<$ty>();
+
+                fn put(&self, buf: &mut [u8]) {
+                    buf[..Self::SIZE].copy_from_slice(&self.to_le_bytes());
+                }
+
+                fn get(buf: &[u8]) -> Option<Self> {
+                    Some(<$ty>::from_le_bytes(buf.get(..Self::SIZE)?.try_into().ok()?))
+                }
+            }
+        )*
+    };
+}
+
+impl_wire_int!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+impl Wire for bool {
+    const SIZE: usize = 1;
+
+    fn put(&self, buf: &mut [u8]) {
+        buf[0] = *self as u8;
+    }
+
+    fn get(buf: &[u8]) -> Option<Self> {
+        match buf.first()? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl Wire for EndpointId {
+    const SIZE: usize = 2;
+
+    fn put(&self, buf: &mut [u8]) {
+        self.raw().put(buf);
+    }
+
+    fn get(buf: &[u8]) -> Option<Self> {
+        u16::get(buf).map(EndpointId::from_raw)
+    }
+}
+
+/// A message type with a fixed tag and payload encoding. Implement it with `ipc_message!`.
+pub trait IpcMessage: Sized {
+    const TAG: MsgTag;
+
+    /// Encode into `buf` and return the number of bytes used.
+    fn encode(&self, buf: &mut [u8; MAX_PAYLOAD]) -> usize;
+
+    fn decode(buf: &[u8]) -> Option<Self>;
+}
+
+impl Message {
+    /// Build a message carrying `body`, with `ty` and `len` filled in from its type. It has
+    /// `PRIO_NORMAL`; use `with_prio` to change that.
+    pub fn encode<T: IpcMessage>(src: EndpointId, dst: EndpointId, seq: u32, body: &T) -> Self {
+        let mut payload = [0u8; MAX_PAYLOAD];
+        let len = body.encode(&mut payload);
+        Message::new(
+            MsgHeader {
+                src,
+                dst,
+                ty: T::TAG,
+                len: len as u8,
+                prio: PRIO_NORMAL,
+                seq,
+            },
+            payload,
+        )
+    }
+
+    /// Decode the payload as a `T`, or `None` if this isn't a `T` or it is malformed.
+    pub fn decode<T: IpcMessage>(&self) -> Option<T> {
+        if self.header.ty != T::TAG {
+            return None;
+        }
+        T::decode(self.payload.get(..self.header.len as usize)?)
+    }
+}
+
+/// Declare an IPC message type and implement `IpcMessage` for it.
+///
+/// Structs encode their fields in declaration order; every field type must implement
+/// `Wire`, and the total size is checked against `MAX_PAYLOAD` at compile time:
+///
+/// ```text
+/// ipc_message! {
+///     pub struct Ping: 1 {
+///         pub seq: u32,
+///     }
+/// }
+/// ```
+///
+/// Enums must be C-like with explicit `u8` discriminants; they encode as that single
+/// byte and also implement `Wire`, so they can be used as struct fields:
+///
+/// ```text
+/// ipc_message! {
+///     pub enum Control: 3 {
+///         Shutdown = 0,
+///         Cancel = 1,
+///     }
+/// }
+/// ```
+///
+/// An enum that is only ever a field, never a message of its own, is declared the same
+/// way with `wire_enum!`, which takes no tag.
+macro_rules! ipc_message {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident : $tag:literal {
+            $( $(#[$fmeta:meta])* $fvis:vis $field:ident : $fty:ty ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+        $vis struct $name {
+            $( $(#[$fmeta])* $fvis $field: $fty, )*
+        }
+
+        const _: () = assert!(
+            0 $( + <$fty as $crate::message::Wire>::SIZE )* <= $crate::ipc::MAX_PAYLOAD,
+            concat!(stringify!($name), " does not fit in an IPC payload"),
+        );
+
+        impl $crate::message::IpcMessage for $name {
+            const TAG: $crate::ipc::MsgTag = $crate::ipc::MsgTag($tag);
+
+            fn encode(&self, buf: &mut [u8; $crate::ipc::MAX_PAYLOAD]) -> usize {
+                #[allow(unused_mut)]
+                let mut off = 0;
+                $(
+                    $crate::message::Wire::put(&self.$field, &mut buf[off..]);
+                    off += <$fty as $crate::message::Wire>::SIZE;
+                )*
+                off
+            }
+
+            // The last field's `off +=` is never read.
+            #[allow(unused_assignments)]
+            fn decode(buf: &[u8]) -> Option<Self> {
+                #[allow(unused_mut, unused_variables)]
+                let mut off = 0;
+                $(
+                    let $field = <$fty as $crate::message::Wire>::get(buf.get(off..)?)?;
+                    off += <$fty as $crate::message::Wire>::SIZE;
+                )*
+                Some(Self { $( $field, )* })
+            }
+        }
+    };
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident : $tag:literal {
+            $( $(#[$vmeta:meta])* $variant:ident = $disc:literal ),* $(,)?
+        }
+    ) => {
+        $crate::message::wire_enum! {
+            $(#[$meta])*
+            $vis enum $name {
+                $( $(#[$vmeta])* $variant = $disc, )*
+            }
+        }
+
+        impl $crate::message::IpcMessage for $name {
+            const TAG: $crate::ipc::MsgTag = $crate::ipc::MsgTag($tag);
+
+            fn encode(&self, buf: &mut [u8; $crate::ipc::MAX_PAYLOAD]) -> usize {
+                $crate::message::Wire::put(self, buf);
+                1
+            }
+
+            fn decode(buf: &[u8]) -> Option<Self> {
+                <Self as $crate::message::Wire>::get(buf)
+            }
+        }
+    };
+}
+
+/// Declare a C-like enum with explicit `u8` discriminants and implement `Wire` for it, as
+/// `ipc_message!` does for enum messages.
+macro_rules! wire_enum {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $( $(#[$vmeta:meta])* $variant:ident = $disc:literal ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+        #[repr(u8)]
+        $vis enum $name {
+            $( $(#[$vmeta])* $variant = $disc, )*
+        }
+
+        impl $crate::message::Wire for $name {
+            const SIZE: usize = 1;
+
+            fn put(&self, buf: &mut [u8]) {
+                buf[0] = *self as u8;
+            }
+
+            fn get(buf: &[u8]) -> Option<Self> {
+                match buf.first()? {
+                    $( $disc => Some(Self::$variant), )*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+pub(crate) use ipc_message;
+pub(crate) use wire_enum;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ipc_message! {
+        struct Sample: 100 {
+            a: u8,
+            b: u16,
+            on: bool,
+            ep: EndpointId,
+        }
+    }
+
+    ipc_message! {
+        enum Control: 101 {
+            Shutdown = 0,
+            Cancel = 1,
+        }
+    }
+
+    const EP: EndpointId = EndpointId::from_raw(0x0102);
+
+    #[test]
+    fn struct_round_trip() {
+        let body = Sample {
+            a: 7,
+            b: 0xBEEF,
+            on: true,
+            ep: EP,
+        };
+        let msg = Message::encode(EP, EP, 3, &body);
+        assert_eq!(msg.header.ty, Sample::TAG);
+        assert_eq!(msg.header.len, 6);
+        assert_eq!(msg.decode::<Sample>(), Some(body));
+    }
+
+    #[test]
+    fn enum_round_trip() {
+        let msg = Message::encode(EP, EP, 0, &Control::Cancel);
+        assert_eq!(msg.decode::<Control>(), Some(Control::Cancel));
+    }
+
+    #[test]
+    fn decode_rejects_wrong_tag_and_bad_payloads() {
+        let mut msg = Message::encode(EP, EP, 0, &Control::Shutdown);
+        assert_eq!(msg.decode::<Sample>(), None);
+        msg.payload[0] = 9;
+        assert_eq!(msg.decode::<Control>(), None);
+
+        let mut msg = Message::encode(
+            EP,
+            EP,
+            0,
+            &Sample {
+                a: 0,
+                b: 0,
+                on: false,
+                ep: EP,
+            },
+        );
+        msg.header.len = 3;
+        assert_eq!(msg.decode::<Sample>(), None);
+    }
+}
+
+
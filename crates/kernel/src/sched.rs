@@ -1,5 +1,41 @@
-use crate::ipc::{self, EndpointId, MsgType};
-use hal::log::Logger;
+use core::fmt::Write;
+
+use crate::cap::Badge;
+use crate::executor::Io;
+use crate::ipc::{self, EndpointId};
+use crate::message::ipc_message;
+use hal::log::{LogWriter, Logger};
+
+ipc_message! {
+    /// Request sent by `ping`; pong echoes `seq` back.
+    pub struct Ping: 1 {
+        pub seq: u32,
+    }
+}
+
+ipc_message! {
+    pub struct Pong: 2 {
+        pub seq: u32,
+    }
+}
+
+// Upper bound on tasks handed to `run`; the router keeps per-task IPC state for each.
+pub const MAX_TASKS: usize = 8;
+
+/// Index of a task in the slice given to `run`. IDs past the end of it are free for
+/// threads to act as (see `thread::Threads::set_task`).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TaskId(u8);
+
+impl TaskId {
+    pub const fn new(index: usize) -> Self {
+        Self(index as u8)
+    }
+
+    pub const fn index(self) -> usize {
+        self.0 as usize
+    }
+}
 
 pub trait Task {
     fn id(&self) -> EndpointId;
@@ -7,115 +43,347 @@ pub trait Task {
 }
 
 pub fn run(tasks: &mut [&mut dyn Task], logger: &dyn Logger, ipc: &mut ipc::Router) -> ! {
-    let mut tick: u64 = 0;
+    assert!(tasks.len() <= MAX_TASKS, "sched: too many tasks");
+    let mut tick = ipc.now();
     logger.log("sched: starting\n");
     loop {
-        for t in tasks.iter_mut() {
-            t.poll(logger, ipc, tick);
+        // With every task parked, nothing can happen until the next interrupt; otherwise
+        // go straight on to the next tick.
+        if !run_tick(tasks, logger, ipc, tick) {
+            hal::arch::halt();
         }
-        tick = tick.wrapping_add(1);
-        // Sleep until the next interrupt (timer tick wakes us).
-        hal::arch::halt();
+        tick = ipc.advance_tick();
     }
 }
 
-pub struct PingTask {
-    seq: u32,
-    waiting: bool,
+/// Like `run`, but returns after `ticks` ticks so tests can drive the scheduler.
+#[cfg(test)]
+pub fn run_for(
+    tasks: &mut [&mut dyn Task],
+    logger: &dyn Logger,
+    ipc: &mut ipc::Router,
+    ticks: u64,
+) {
+    assert!(tasks.len() <= MAX_TASKS, "sched: too many tasks");
+    let mut tick = ipc.now();
+    for _ in 0..ticks {
+        if !run_tick(tasks, logger, ipc, tick) {
+            hal::arch::halt();
+        }
+        tick = ipc.advance_tick();
+    }
 }
 
-impl PingTask {
-    pub const fn new() -> Self {
-        Self {
-            seq: 1,
-            waiting: false,
+/// Poll the tasks due this tick. Returns whether any task is still runnable, i.e. not
+/// parked in a blocking call or waiting for a deadline.
+fn run_tick(
+    tasks: &mut [&mut dyn Task],
+    logger: &dyn Logger,
+    ipc: &mut ipc::Router,
+    tick: u64,
+) -> bool {
+    // Every runnable task gets one poll per tick. Tasks parked in a blocking IPC call
+    // are skipped; if a send wakes one mid-tick it is polled again before we sleep, so
+    // a request and its reply don't each cost a full timer period.
+    let mut due: u32 = u32::MAX;
+    while due != 0 {
+        // Interrupts raised since the last pass wake their drivers like any other event,
+        // and faults go to their pagers.
+        ipc.deliver_irqs();
+        ipc.deliver_faults();
+        due |= ipc.take_woken();
+        for (i, t) in tasks.iter_mut().enumerate() {
+            let id = TaskId::new(i);
+            if due & (1 << i) == 0 || !ipc.is_runnable(id) {
+                continue;
+            }
+            ipc.set_current(id);
+            t.poll(logger, ipc, tick);
         }
+        due = ipc.take_woken();
     }
+    (0..tasks.len()).any(|i| ipc.is_runnable(TaskId::new(i)))
 }
 
-impl Task for PingTask {
-    fn id(&self) -> EndpointId {
-        EndpointId::Ping
-    }
+// Ticks ping waits for each pong before giving up on it.
+const PONG_TIMEOUT: u64 = 5;
 
-    fn poll(&mut self, logger: &dyn Logger, ipc: &mut ipc::Router, tick: u64) {
-        if tick == 0 {
-            logger.log("task/ping: poll\n");
-        }
-        // Check replies first.
-        if let Some(msg) = ipc.recv(self.id()) {
-            if matches!(msg.header.ty, MsgType::Pong) {
-                self.waiting = false;
+/// Calls pong about once every 10 ticks and logs how each call went. Runs as an
+/// `executor::AsyncTask` on `io`, which should own the endpoint ping receives on;
+/// `peer_badge` is the badge pong's replies carry, and anything else didn't come from pong.
+pub async fn ping(io: &Io, logger: &dyn Logger, peer: EndpointId, peer_badge: Badge) {
+    logger.log("task/ping: poll\n");
+    let mut seq: u32 = 1;
+    loop {
+        let tick = io.now();
+        let ping = Ping { seq };
+        let msg = ipc::Message::encode(io.id(), peer, seq, &ping);
+        let reply = match io.call_timeout(msg, tick + PONG_TIMEOUT) {
+            Ok(reply) => reply,
+            Err(e) => {
+                let _ = writeln!(LogWriter(logger), "task/ping: send failed ({:?})", e);
+                // Try again next tick.
+                io.sleep(1).await;
+                continue;
+            }
+        };
+        logger.log("task/ping: sent ping\n");
+        seq = seq.wrapping_add(1);
+
+        match reply.await {
+            Ok(msg) if msg.badge == peer_badge && msg.decode::<Pong>().is_some() => {
                 logger.log("task/ping: got pong\n");
             }
+            Err(ipc::RecvError::Timeout) => {
+                logger.log("task/ping: pong timed out\n");
+                io.dump_trace(logger);
+            }
+            _ => {}
         }
 
-        // Send a ping periodically when not waiting for a reply.
-        // (Tuned to be visible even without a real timer interrupt.)
         // With a 100ms timer tick, this sends roughly once every ~1s.
-        if !self.waiting && (tick % 10) == 0 {
-            let mut payload = [0u8; ipc::MAX_PAYLOAD];
-            ipc::write_u32_le(&mut payload[0..4], self.seq);
-            let msg = ipc::Message {
-                header: ipc::MsgHeader {
-                    src: EndpointId::Ping,
-                    dst: EndpointId::Pong,
-                    ty: MsgType::Ping,
-                    len: 4,
-                    seq: self.seq,
-                },
-                payload,
-            };
-
-            match ipc.send(msg) {
-                Ok(()) => {
-                    logger.log("task/ping: sent ping\n");
-                    self.waiting = true;
-                    self.seq = self.seq.wrapping_add(1);
-                }
-                Err(_) => {
-                    logger.log("task/ping: send failed (queue full)\n");
-                }
-            }
-        }
+        io.sleep_until(tick + 10).await;
     }
 }
 
-pub struct PongTask;
+pub struct PongTask {
+    ep: EndpointId,
+}
 
 impl PongTask {
-    pub const fn new() -> Self {
-        Self
+    pub const fn new(ep: EndpointId) -> Self {
+        Self { ep }
     }
 }
 
 impl Task for PongTask {
     fn id(&self) -> EndpointId {
-        EndpointId::Pong
+        self.ep
     }
 
     fn poll(&mut self, logger: &dyn Logger, ipc: &mut ipc::Router, _tick: u64) {
-        if let Some(msg) = ipc.recv(self.id()) {
-            if matches!(msg.header.ty, MsgType::Ping) {
-                let seq = ipc::read_u32_le(&msg.payload[0..4]);
-                logger.log("task/pong: got ping\n");
-
-                let mut payload = [0u8; ipc::MAX_PAYLOAD];
-                ipc::write_u32_le(&mut payload[0..4], seq);
-                let reply = ipc::Message {
-                    header: ipc::MsgHeader {
-                        src: EndpointId::Pong,
-                        dst: EndpointId::Ping,
-                        ty: MsgType::Pong,
-                        len: 4,
-                        seq,
-                    },
-                    payload,
-                };
-                let _ = ipc.send(reply);
+        // Drain the mailbox; once it's empty `recv_blocking` parks us until the next ping.
+        let mut next = ipc.recv_blocking(self.id());
+        while let Ok(Some(msg)) = next {
+            // Pings arrive via `call`; anything without a reply cap has nobody to answer.
+            // Clients are told apart by badge: `header.src` is whatever the sender wrote,
+            // and an unbadged cap was never handed to a client.
+            let (Some(ping), Some(cap)) = (msg.decode::<Ping>(), msg.reply) else {
+                next = ipc.recv_blocking(self.id());
+                continue;
+            };
+            if msg.badge == Badge::NONE {
+                next = ipc.recv_blocking(self.id());
+                continue;
             }
+            let _ = writeln!(
+                LogWriter(logger),
+                "task/pong: got ping from client {}",
+                msg.badge.0
+            );
+
+            let pong = Pong { seq: ping.seq };
+            let reply = ipc::Message::encode(self.ep, msg.header.src, ping.seq, &pong);
+            next = ipc.reply_recv(self.id(), cap, reply);
+        }
+    }
+}
+
+/// Hears about faults taken by the tasks it is the pager of, logs them and kills the
+/// task: nothing in the demo knows how to fix a fault. Runs as an `executor::AsyncTask`
+/// on `io`, which should own the endpoint the faults are sent to.
+pub async fn pager(io: &Io, logger: &dyn Logger) {
+    loop {
+        let Ok(msg) = io.recv(io.id()).await else {
+            logger.log("task/pager: endpoint gone\n");
+            return;
+        };
+        // Only the kernel sends faults, and they always come with a reply cap.
+        let (Some(fault), Some(cap)) = (msg.decode::<ipc::Fault>(), msg.reply) else {
+            continue;
+        };
+        let _ = writeln!(
+            LogWriter(logger),
+            "task/pager: task {} faulted at {:#x} ({:?}), killing it",
+            fault.task,
+            fault.addr,
+            fault.access
+        );
+        let kill = ipc::Message::encode(io.id(), io.id(), 0, &ipc::FaultReply::Kill);
+        let _ = io.reply(cap, kill);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::pin::pin;
+
+    use super::*;
+    use crate::cap::Rights;
+    use crate::executor::AsyncTask;
+    use crate::ipc::{FaultAccess, FaultInfo, FaultSlots, FaultStatus};
+    use crate::testutil::{grant, grant_badged, leak, MockLogger};
+
+    /// Blocks on its endpoint and counts how often it gets polled.
+    struct Sleeper {
+        ep: EndpointId,
+        polls: usize,
+    }
+
+    impl Task for Sleeper {
+        fn id(&self) -> EndpointId {
+            self.ep
+        }
+
+        fn poll(&mut self, _logger: &dyn Logger, ipc: &mut ipc::Router, _tick: u64) {
+            self.polls += 1;
+            while let Ok(Some(_)) = ipc.recv_blocking(self.ep) {}
         }
     }
+
+    #[test]
+    fn ping_pong_exchange() {
+        let mut router = ipc::Router::new();
+        let ping_ep = router.create_endpoint(1).unwrap();
+        let pong_ep = router.create_endpoint(4).unwrap();
+        grant(&mut router, 0, ping_ep, Rights::RECV);
+        grant_badged(&mut router, 0, pong_ep, Rights::SEND, Badge(7));
+        grant_badged(&mut router, 1, pong_ep, Rights::RECV, Badge(9));
+
+        let log = MockLogger::default();
+        let io = Io::new(ping_ep);
+        let fut = pin!(ping(&io, &log, pong_ep, Badge(9)));
+        let mut ping_task = AsyncTask::new(&io, fut);
+        let mut pong = PongTask::new(pong_ep);
+        let mut tasks: [&mut dyn Task; 2] = [&mut ping_task, &mut pong];
+        run_for(&mut tasks, &log, &mut router, 25);
+
+        // Pings go out at ticks 0, 10 and 20, and each is answered within its tick.
+        assert_eq!(log.count("task/ping: sent ping\n"), 3);
+        assert_eq!(log.count("task/pong: got ping from client 7\n"), 3);
+        assert_eq!(log.count("task/ping: got pong\n"), 3);
+        assert_eq!(log.count("timed out"), 0);
+    }
+
+    #[test]
+    fn ping_times_out_without_pong() {
+        let mut router = ipc::Router::new();
+        let ping_ep = router.create_endpoint(1).unwrap();
+        let pong_ep = router.create_endpoint(4).unwrap();
+        grant(&mut router, 0, pong_ep, Rights::SEND);
+
+        let log = MockLogger::default();
+        let io = Io::new(ping_ep);
+        let fut = pin!(ping(&io, &log, pong_ep, Badge(9)));
+        let mut ping_task = AsyncTask::new(&io, fut);
+        let mut tasks: [&mut dyn Task; 1] = [&mut ping_task];
+        run_for(&mut tasks, &log, &mut router, PONG_TIMEOUT + 1);
+
+        assert_eq!(log.count("task/ping: pong timed out\n"), 1);
+    }
+
+    #[test]
+    fn pong_ignores_unbadged_and_ping_rejects_foreign_replies() {
+        let mut router = ipc::Router::new();
+        let ping_ep = router.create_endpoint(1).unwrap();
+        let pong_ep = router.create_endpoint(4).unwrap();
+        grant(&mut router, 0, pong_ep, Rights::SEND);
+        grant_badged(&mut router, 1, pong_ep, Rights::RECV, Badge(9));
+
+        let log = MockLogger::default();
+        let io = Io::new(ping_ep);
+        let fut = pin!(ping(&io, &log, pong_ep, Badge(9)));
+        let mut ping_task = AsyncTask::new(&io, fut);
+        let mut pong = PongTask::new(pong_ep);
+        let mut tasks: [&mut dyn Task; 2] = [&mut ping_task, &mut pong];
+        run_for(&mut tasks, &log, &mut router, 3);
+        assert_eq!(log.count("task/pong: got ping"), 0);
+        assert_eq!(log.count("task/ping: pong timed out"), 0);
+
+        let mut router = ipc::Router::new();
+        let ping_ep = router.create_endpoint(1).unwrap();
+        let pong_ep = router.create_endpoint(4).unwrap();
+        grant_badged(&mut router, 0, pong_ep, Rights::SEND, Badge(7));
+        grant_badged(&mut router, 1, pong_ep, Rights::RECV, Badge(8));
+
+        let log = MockLogger::default();
+        let io = Io::new(ping_ep);
+        let fut = pin!(ping(&io, &log, pong_ep, Badge(9)));
+        let mut ping_task = AsyncTask::new(&io, fut);
+        let mut pong = PongTask::new(pong_ep);
+        let mut tasks: [&mut dyn Task; 2] = [&mut ping_task, &mut pong];
+        run_for(&mut tasks, &log, &mut router, 3);
+        assert_eq!(log.count("task/pong: got ping from client 7\n"), 1);
+        assert_eq!(log.count("task/ping: got pong"), 0);
+    }
+
+    #[test]
+    fn parked_tasks_are_not_polled() {
+        let mut router = ipc::Router::new();
+        let ep = router.create_endpoint(1).unwrap();
+        grant(&mut router, 0, ep, Rights::RECV);
+
+        let mut sleeper = Sleeper { ep, polls: 0 };
+        let mut tasks: [&mut dyn Task; 1] = [&mut sleeper];
+        run_for(&mut tasks, &MockLogger::default(), &mut router, 5);
+        assert_eq!(sleeper.polls, 1);
+    }
+
+    /// Never blocks, so it wants polling on every tick.
+    struct Spinner(EndpointId);
+
+    impl Task for Spinner {
+        fn id(&self) -> EndpointId {
+            self.0
+        }
+
+        fn poll(&mut self, _logger: &dyn Logger, _ipc: &mut ipc::Router, _tick: u64) {}
+    }
+
+    #[test]
+    fn idle_only_once_every_task_is_parked() {
+        let mut router = ipc::Router::new();
+        let ep = router.create_endpoint(1).unwrap();
+        grant(&mut router, 0, ep, Rights::RECV);
+        let log = MockLogger::default();
+
+        let mut sleeper = Sleeper { ep, polls: 0 };
+        let mut spinner = Spinner(ep);
+        let mut tasks: [&mut dyn Task; 2] = [&mut sleeper, &mut spinner];
+        assert!(run_tick(&mut tasks, &log, &mut router, 0));
+        assert!(!run_tick(&mut tasks[..1], &log, &mut router, 1));
+    }
+
+    #[test]
+    fn pager_kills_faulting_tasks() {
+        let slots = leak(FaultSlots::new());
+        let mut router = ipc::Router::new();
+        router.set_fault_slots(slots);
+        let ep = router.create_endpoint(1).unwrap();
+        grant(&mut router, 0, ep, Rights::RECV);
+        // Task 1 is not in the slice: it stands for a thread.
+        let thread = TaskId::new(1);
+        router.set_pager(thread, ep).unwrap();
+
+        let log = MockLogger::default();
+        let io = Io::new(ep);
+        let fut = pin!(pager(&io, &log));
+        let mut pager_task = AsyncTask::new(&io, fut);
+        let mut tasks: [&mut dyn Task; 1] = [&mut pager_task];
+        run_for(&mut tasks, &log, &mut router, 1);
+        let fault = FaultInfo {
+            addr: 0xc000_0000,
+            access: FaultAccess::Read,
+            class: 0x25,
+        };
+        slots.raise(thread, fault);
+        run_for(&mut tasks, &log, &mut router, 1);
+        assert_eq!(
+            log.count("task/pager: task 1 faulted at 0xc0000000 (Read), killing it\n"),
+            1
+        );
+        assert_eq!(slots.status(thread), FaultStatus::Killed);
+    }
 }
 
 
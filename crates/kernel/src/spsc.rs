@@ -0,0 +1,228 @@
+//! Lock-free single-producer/single-consumer ring.
+//!
+//! For handing data from interrupt context to a task: the IRQ handler owns the
+//! `Producer`, a task owns the `Consumer`, and neither ever waits for the other. Unlike
+//! `ROUTER`, a queue can live in a plain `static` and be touched from both sides.
+//!
+//! `head` and `tail` count pops and pushes since creation and are only ever advanced by
+//! their own side, so each side reads the other's counter with `Acquire` and publishes
+//! its own with `Release`.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+pub struct SpscQueue<T, const N: usize> {
+    buf: [UnsafeCell<MaybeUninit<T>>; N],
+    // Number of items popped; the next pop reads `buf[head % N]`.
+    head: AtomicUsize,
+    // Number of items pushed; the next push writes `buf[tail % N]`.
+    tail: AtomicUsize,
+    split: AtomicBool,
+}
+
+// Each slot is only accessed by one side at a time, as arbitrated by `head`/`tail`.
+unsafe impl<T: Copy + Send, const N: usize> Sync for SpscQueue<T, N> {}
+
+impl<T: Copy, const N: usize> SpscQueue<T, N> {
+    /// An empty queue holding up to `N` items. `N` must be a power of two, so the
+    /// counters stay consistent with slot indices when they wrap.
+    pub const fn new() -> Self {
+        assert!(N.is_power_of_two(), "spsc: capacity must be a power of two");
+        Self {
+            buf: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            split: AtomicBool::new(false),
+        }
+    }
+
+    /// Hand out the queue's two ends. Only the first call succeeds, which is what makes
+    /// the producer and consumer unique.
+    pub fn split(&self) -> Option<(Producer<'_, T, N>, Consumer<'_, T, N>)> {
+        if self.split.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        Some((Producer { q: self }, Consumer { q: self }))
+    }
+
+    /// Number of queued items. Only a snapshot while the other side is active.
+    pub fn len(&self) -> usize {
+        // `head` first: it never passes `tail`, so a `tail` read afterwards can't be behind
+        // it. Pushes and pops in between can still take the difference past `N`.
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        tail.wrapping_sub(head).min(N)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<T: Copy, const N: usize> Default for SpscQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The pushing end; safe to use from an interrupt handler.
+pub struct Producer<'a, T, const N: usize> {
+    q: &'a SpscQueue<T, N>,
+}
+
+impl<T: Copy, const N: usize> Producer<'_, T, N> {
+    /// Queue `value`, or hand it back if the queue is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let tail = self.q.tail.load(Ordering::Relaxed);
+        let head = self.q.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == N {
+            return Err(value);
+        }
+        // The consumer won't read this slot until it sees the new `tail`.
+        unsafe { (*self.q.buf[tail % N].get()).write(value) };
+        self.q.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+}
+
+pub struct Consumer<'a, T, const N: usize> {
+    q: &'a SpscQueue<T, N>,
+}
+
+impl<T: Copy, const N: usize> Consumer<'_, T, N> {
+    /// Take the oldest item, or `None` if the queue is empty.
+    pub fn pop(&mut self) -> Option<T> {
+        let head = self.q.head.load(Ordering::Relaxed);
+        let tail = self.q.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        // The producer wrote this slot before publishing `tail`, and won't reuse it
+        // until it sees the new `head`.
+        let value = unsafe { (*self.q.buf[head % N].get()).assume_init() };
+        self.q.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_and_full() {
+        let q: SpscQueue<u32, 4> = SpscQueue::new();
+        let (mut tx, mut rx) = q.split().unwrap();
+        assert!(q.is_empty());
+        assert_eq!(rx.pop(), None);
+        for i in 0..4 {
+            tx.push(i).unwrap();
+        }
+        assert_eq!(q.len(), 4);
+        assert_eq!(tx.push(99), Err(99));
+        assert_eq!(rx.pop(), Some(0));
+        tx.push(4).unwrap();
+        assert_eq!(tx.push(5), Err(5));
+    }
+
+    #[test]
+    fn wraps_around_in_order() {
+        let q: SpscQueue<u32, 4> = SpscQueue::new();
+        let (mut tx, mut rx) = q.split().unwrap();
+        let mut next = 0;
+        for round in 0..10u32 {
+            for i in 0..3 {
+                tx.push(round * 3 + i).unwrap();
+            }
+            for _ in 0..3 {
+                assert_eq!(rx.pop(), Some(next));
+                next += 1;
+            }
+        }
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn counters_survive_wrapping() {
+        let q: SpscQueue<u8, 2> = SpscQueue::new();
+        q.head.store(usize::MAX - 1, Ordering::Relaxed);
+        q.tail.store(usize::MAX - 1, Ordering::Relaxed);
+        let (mut tx, mut rx) = q.split().unwrap();
+        for i in 0..5 {
+            tx.push(i).unwrap();
+            tx.push(i + 100).unwrap();
+            assert_eq!(tx.push(0), Err(0));
+            assert_eq!(rx.pop(), Some(i));
+            assert_eq!(rx.pop(), Some(i + 100));
+            assert_eq!(rx.pop(), None);
+        }
+    }
+
+    #[test]
+    fn len_stays_within_capacity_while_both_sides_run() {
+        static Q: SpscQueue<u32, 4> = SpscQueue::new();
+        const COUNT: u32 = 10_000;
+        let (mut tx, mut rx) = Q.split().unwrap();
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..COUNT {
+                    while tx.push(i).is_err() {
+                        std::thread::yield_now();
+                    }
+                }
+            });
+            let consumer = s.spawn(move || {
+                for _ in 0..COUNT {
+                    while rx.pop().is_none() {
+                        std::thread::yield_now();
+                    }
+                }
+            });
+            while !consumer.is_finished() {
+                assert!(Q.len() <= Q.capacity());
+                std::thread::yield_now();
+            }
+        });
+        assert!(Q.is_empty());
+    }
+
+    #[test]
+    fn splits_only_once() {
+        let q: SpscQueue<u8, 2> = SpscQueue::new();
+        assert!(q.split().is_some());
+        assert!(q.split().is_none());
+    }
+
+    #[test]
+    fn concurrent_producer_and_consumer() {
+        static Q: SpscQueue<u32, 8> = SpscQueue::new();
+        const COUNT: u32 = 10_000;
+        let (mut tx, mut rx) = Q.split().unwrap();
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..COUNT {
+                    while tx.push(i).is_err() {
+                        std::thread::yield_now();
+                    }
+                }
+            });
+            let mut expected = 0;
+            while expected < COUNT {
+                match rx.pop() {
+                    Some(v) => {
+                        assert_eq!(v, expected);
+                        expected += 1;
+                    }
+                    None => std::thread::yield_now(),
+                }
+            }
+        });
+    }
+}
+
+
@@ -0,0 +1,118 @@
+//! Helpers shared by the kernel's host unit tests.
+
+use std::boxed::Box;
+use std::cell::RefCell;
+use std::string::String;
+use std::sync::Mutex;
+use std::vec;
+use std::vec::Vec;
+
+use hal::log::Logger;
+use hal::mem::FrameAllocator;
+
+use crate::cap::{Badge, Capability, Object, Rights};
+use crate::ipc::{
+    EndpointId, Message, MsgHeader, MsgTag, Router, MAX_PAYLOAD, PAGE_SIZE, PRIO_NORMAL,
+};
+use crate::sched::TaskId;
+
+/// Logger that keeps everything written to it.
+#[derive(Default)]
+pub struct MockLogger {
+    out: RefCell<String>,
+}
+
+impl MockLogger {
+    pub fn output(&self) -> String {
+        self.out.borrow().clone()
+    }
+
+    /// Number of times `line` was logged.
+    pub fn count(&self, line: &str) -> usize {
+        self.out.borrow().matches(line).count()
+    }
+}
+
+impl Logger for MockLogger {
+    fn log(&self, s: &str) {
+        self.out.borrow_mut().push_str(s);
+    }
+}
+
+/// Leaks real, page-aligned memory for each allocation, up to a budget of frames, and
+/// records frees.
+pub struct MockFrames {
+    budget: Mutex<usize>,
+    freed: Mutex<Vec<(u64, usize)>>,
+}
+
+impl MockFrames {
+    /// Every `(phys, count)` freed so far, oldest first.
+    pub fn freed(&self) -> Vec<(u64, usize)> {
+        self.freed.lock().unwrap().clone()
+    }
+}
+
+impl FrameAllocator for MockFrames {
+    fn alloc_frames(&self, count: usize) -> Option<u64> {
+        let mut budget = self.budget.lock().unwrap();
+        *budget = budget.checked_sub(count)?;
+        let mem = vec![0u8; (count + 1) * PAGE_SIZE as usize].leak();
+        Some((mem.as_mut_ptr() as u64).next_multiple_of(PAGE_SIZE))
+    }
+
+    fn free_frames(&self, phys: u64, count: usize) {
+        *self.budget.lock().unwrap() += count;
+        self.freed.lock().unwrap().push((phys, count));
+    }
+}
+
+pub fn frames(budget: usize) -> &'static MockFrames {
+    leak(MockFrames {
+        budget: Mutex::new(budget),
+        freed: Mutex::new(Vec::new()),
+    })
+}
+
+/// `value` for the rest of the test run, for the `'static` tables the router is handed
+/// at boot.
+pub fn leak<T>(value: T) -> &'static T {
+    Box::leak(Box::new(value))
+}
+
+/// A message from `src` to `dst` with a raw tag and `seq`, and an empty payload.
+pub fn msg(src: EndpointId, dst: EndpointId, ty: u16, seq: u32) -> Message {
+    Message::new(
+        MsgHeader {
+            src,
+            dst,
+            ty: MsgTag(ty),
+            len: 0,
+            prio: PRIO_NORMAL,
+            seq,
+        },
+        [0; MAX_PAYLOAD],
+    )
+}
+
+/// Install an unbadged capability for `task` while the router is still in kernel context.
+pub fn grant(router: &mut Router, task: usize, obj: impl Into<Object>, rights: Rights) {
+    grant_badged(router, task, obj, rights, Badge::NONE);
+}
+
+pub fn grant_badged(
+    router: &mut Router,
+    task: usize,
+    obj: impl Into<Object>,
+    rights: Rights,
+    badge: Badge,
+) {
+    let cap = Capability {
+        obj: obj.into(),
+        rights,
+        badge,
+    };
+    router.install_cap(TaskId::new(task), cap).unwrap();
+}
+
+
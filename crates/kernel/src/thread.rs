@@ -0,0 +1,782 @@
+//! Threads: flows of control with their own stacks, preempted on timer ticks.
+//!
+//! The kernel keeps one `Tcb` per thread: its state, its priority and a handle to its
+//! saved registers. That handle is opaque here. The arch's interrupt entry code saves a
+//! thread's registers on every interrupt and passes the kernel the handle. The kernel
+//! replies with the handle of the thread to resume. Building a fresh context and starting
+//! the first thread are the only other arch hooks (`hal::thread::ThreadArch`).
+//!
+//! Scheduling is fixed-priority: each priority level has a FIFO run queue, and a bitmap
+//! of non-empty queues makes picking the next thread a find-highest-bit. Threads of the
+//! same priority share the CPU round-robin, one time slice each (configurable per
+//! level). A thread never runs while a higher-priority one is ready: one that becomes
+//! ready takes over at once, without waiting for the running thread's slice to end. A
+//! sleeper is woken by the tick that switches to it; for a new thread or one its pager
+//! resumed, `spawn` and `wake_resumed` let the caller know it should yield.
+//!
+//! A thread can sleep until a deadline, in ticks of the same clock as IPC deadlines.
+//! Sleepers sit in a timer queue kept in deadline order, so a tick only looks at the
+//! ones that are due.
+//!
+//! Cooperative `sched::Task`s run on top of this: `kmain`'s scheduler loop is simply one
+//! of the threads, and polls its tasks on its own stack.
+//!
+//! A thread can be given an IPC identity of its own, a task ID that no cooperative task
+//! uses (`set_task`). A fault the thread takes is then forwarded to that task's pager (see
+//! `ipc::fault`); a thread without one has no pager to ask and is killed.
+//!
+//! Stacks come from the platform's frame allocator. A thread that calls `exit` is
+//! reaped at a later switch, once nothing runs on its stack any more: the frames go back
+//! to the allocator and the slot (and with it the ID) can be reused. IPC state the router
+//! keeps for the thread's task is not reset, so its task ID is not reused (see
+//! `supervise_thread`).
+
+#![allow(dead_code)]
+
+use hal::mem::FrameAllocator;
+use hal::thread::{ThreadArch, ThreadEntry};
+
+use crate::ipc::{FaultStatus, PAGE_SIZE};
+use crate::sched::{TaskId, MAX_TASKS};
+
+pub const MAX_THREADS: usize = MAX_TASKS;
+
+/// Number of thread priorities; `0` is the lowest and `PRIORITIES - 1` the highest.
+pub const PRIORITIES: usize = 8;
+
+// Default timer ticks a thread may run before the next ready thread of its priority
+// gets the CPU.
+pub const TIME_SLICE: u32 = 5;
+
+const _: () = assert!(MAX_THREADS <= 32 && PRIORITIES <= 32);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ThreadId(u8);
+
+impl ThreadId {
+    pub const fn new(index: usize) -> Self {
+        Self(index as u8)
+    }
+
+    pub const fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// Handle to a thread's saved registers, as handed out by `ThreadArch::init`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ArchContext(pub usize);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ThreadState {
+    /// In its priority's run queue, waiting for its turn on the CPU.
+    Ready,
+    Running,
+    /// Took a fault; held off the CPU until its pager resumes it.
+    Suspended,
+    /// In the timer queue until its deadline.
+    Sleeping,
+    /// Called `exit`; waiting to be reaped.
+    Dead,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ThreadError {
+    /// Every thread slot is in use (for `supervise_thread`: every task ID for threads).
+    TableFull,
+    /// No `ThreadArch` or `FrameAllocator` is registered on this platform.
+    Unsupported,
+    /// The frame allocator has no room for the stack.
+    OutOfMemory,
+    /// The priority is not below `PRIORITIES`.
+    BadPriority,
+    NoSuchThread,
+}
+
+/// Thread control block.
+#[derive(Copy, Clone)]
+pub struct Tcb {
+    pub state: ThreadState,
+    pub priority: u8,
+    /// The task this thread acts as for IPC, if it was given one.
+    pub task: Option<TaskId>,
+    context: ArchContext,
+    // Physical address and frame count of the stack, returned when the thread is reaped.
+    stack: (u64, usize),
+}
+
+/// FIFO of ready threads of one priority.
+#[derive(Copy, Clone)]
+struct RunQueue {
+    ids: [u8; MAX_THREADS],
+    head: usize,
+    len: usize,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            ids: [0; MAX_THREADS],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    // A thread is queued at most once, so the queue never overflows.
+    fn push_back(&mut self, id: ThreadId) {
+        self.ids[(self.head + self.len) % MAX_THREADS] = id.0;
+        self.len += 1;
+    }
+
+    fn push_front(&mut self, id: ThreadId) {
+        self.head = (self.head + MAX_THREADS - 1) % MAX_THREADS;
+        self.ids[self.head] = id.0;
+        self.len += 1;
+    }
+
+    fn pop_front(&mut self) -> Option<ThreadId> {
+        if self.len == 0 {
+            return None;
+        }
+        let id = ThreadId(self.ids[self.head]);
+        self.head = (self.head + 1) % MAX_THREADS;
+        self.len -= 1;
+        Some(id)
+    }
+}
+
+/// Sleeping threads, ordered by deadline: latest first, so the next one due is at the
+/// end. Threads with the same deadline wake in the order they went to sleep.
+struct TimerQueue {
+    entries: [(u64, ThreadId); MAX_THREADS],
+    len: usize,
+}
+
+impl TimerQueue {
+    const fn new() -> Self {
+        Self {
+            entries: [(0, ThreadId(0)); MAX_THREADS],
+            len: 0,
+        }
+    }
+
+    // A thread sleeps at most once, so the queue never overflows.
+    fn insert(&mut self, deadline: u64, id: ThreadId) {
+        let pos = self.entries[..self.len].partition_point(|&(d, _)| d > deadline);
+        self.entries.copy_within(pos..self.len, pos + 1);
+        self.entries[pos] = (deadline, id);
+        self.len += 1;
+    }
+
+    /// The next thread whose deadline is at or before `now`.
+    fn pop_due(&mut self, now: u64) -> Option<ThreadId> {
+        let &(deadline, id) = self.entries[..self.len].last()?;
+        if deadline > now {
+            return None;
+        }
+        self.len -= 1;
+        Some(id)
+    }
+}
+
+/// The thread table and the fixed-priority scheduler over it.
+pub struct Threads {
+    tcbs: [Option<Tcb>; MAX_THREADS],
+    // `None` until `start`. The running thread is in no run queue.
+    current: Option<ThreadId>,
+    // Ticks left in the current thread's slice.
+    slice_left: u32,
+    queues: [RunQueue; PRIORITIES],
+    // Bit `p` is set when `queues[p]` is not empty.
+    ready: u32,
+    // Bitmask of threads in `ThreadState::Suspended`.
+    suspended: u32,
+    slices: [u32; PRIORITIES],
+    sleepers: TimerQueue,
+    // Time base for sleeps: the platform's tick counter if registered, else `tick`
+    // counts its own calls.
+    clock: Option<fn() -> u64>,
+    ticks: u64,
+    arch: Option<&'static dyn ThreadArch>,
+    frames: Option<&'static dyn FrameAllocator>,
+}
+
+impl Threads {
+    pub const fn new() -> Self {
+        Self {
+            tcbs: [None; MAX_THREADS],
+            current: None,
+            slice_left: 0,
+            queues: [RunQueue::new(); PRIORITIES],
+            ready: 0,
+            suspended: 0,
+            slices: [TIME_SLICE; PRIORITIES],
+            sleepers: TimerQueue::new(),
+            clock: None,
+            ticks: 0,
+            arch: None,
+            frames: None,
+        }
+    }
+
+    pub fn set_arch(&mut self, arch: &'static dyn ThreadArch) {
+        self.arch = Some(arch);
+    }
+
+    pub fn arch(&self) -> Option<&'static dyn ThreadArch> {
+        self.arch
+    }
+
+    pub fn set_frame_allocator(&mut self, frames: &'static dyn FrameAllocator) {
+        self.frames = Some(frames);
+    }
+
+    pub fn set_clock(&mut self, clock: fn() -> u64) {
+        self.clock = Some(clock);
+    }
+
+    /// Current tick, the time base for `sleep_current`.
+    pub fn now(&self) -> u64 {
+        self.clock.map_or(self.ticks, |clock| clock())
+    }
+
+    /// Let threads of `priority` run `ticks` timer ticks at a time (at least one).
+    pub fn set_time_slice(&mut self, priority: u8, ticks: u32) -> Result<(), ThreadError> {
+        let slice = self
+            .slices
+            .get_mut(priority as usize)
+            .ok_or(ThreadError::BadPriority)?;
+        *slice = ticks.max(1);
+        Ok(())
+    }
+
+    /// Create a thread that runs `entry(arg)` on a fresh stack of at least `stack_size`
+    /// bytes. It is ready immediately; see `should_preempt` for whether it ought to
+    /// displace the caller.
+    pub fn spawn(
+        &mut self,
+        entry: ThreadEntry,
+        arg: usize,
+        stack_size: usize,
+        priority: u8,
+    ) -> Result<ThreadId, ThreadError> {
+        let (Some(arch), Some(frames)) = (self.arch, self.frames) else {
+            return Err(ThreadError::Unsupported);
+        };
+        if priority as usize >= PRIORITIES {
+            return Err(ThreadError::BadPriority);
+        }
+        self.reap(self.current);
+        let slot = self
+            .tcbs
+            .iter()
+            .position(|t| t.is_none())
+            .ok_or(ThreadError::TableFull)?;
+        let count = stack_size.div_ceil(PAGE_SIZE as usize).max(1);
+        let phys = frames.alloc_frames(count).ok_or(ThreadError::OutOfMemory)?;
+        // SAFETY: the frames are ours alone until the thread is reaped, and the allocator
+        // guarantees they are accessible at their physical address.
+        let stack =
+            unsafe { core::slice::from_raw_parts_mut(phys as *mut u8, count * PAGE_SIZE as usize) };
+        self.tcbs[slot] = Some(Tcb {
+            state: ThreadState::Ready,
+            priority,
+            task: None,
+            context: ArchContext(arch.init(stack, entry, arg)),
+            stack: (phys, count),
+        });
+        let id = ThreadId::new(slot);
+        self.enqueue(id, false);
+        Ok(id)
+    }
+
+    /// Let `id` act as `task` for IPC; its faults then go to that task's pager.
+    pub fn set_task(&mut self, id: ThreadId, task: TaskId) -> Result<(), ThreadError> {
+        let t = self
+            .tcbs
+            .get_mut(id.index())
+            .and_then(Option::as_mut)
+            .ok_or(ThreadError::NoSuchThread)?;
+        t.task = Some(task);
+        Ok(())
+    }
+
+    /// Mark the current thread dead. It is never picked again, and is reaped once
+    /// another thread has taken over the CPU; the caller should yield right away.
+    pub fn exit_current(&mut self) {
+        if let Some(t) = self.current.and_then(|c| self.tcbs[c.index()].as_mut()) {
+            t.state = ThreadState::Dead;
+        }
+    }
+
+    /// Put the current thread to sleep until tick `deadline`. Returns false, leaving it
+    /// running, if the deadline has already passed. Otherwise the caller should yield:
+    /// the thread stays off the CPU until a `tick` at or after the deadline.
+    pub fn sleep_current(&mut self, deadline: u64) -> bool {
+        if deadline <= self.now() {
+            return false;
+        }
+        let Some(cur) = self.current else {
+            return false;
+        };
+        let Some(t) = &mut self.tcbs[cur.index()] else {
+            return false;
+        };
+        t.state = ThreadState::Sleeping;
+        self.sleepers.insert(deadline, cur);
+        true
+    }
+
+    /// Whether the current thread is asleep, i.e. still waiting for its deadline.
+    pub fn current_sleeping(&self) -> bool {
+        self.current
+            .and_then(|c| self.tcbs[c.index()])
+            .is_some_and(|t| t.state == ThreadState::Sleeping)
+    }
+
+    /// Let another ready thread of the same (or higher) priority run before the current
+    /// one's slice is up.
+    pub fn yield_current(&mut self, status: impl Fn(TaskId) -> FaultStatus) -> Option<ArchContext> {
+        self.current?;
+        self.switch(status, false)
+    }
+
+    /// Queue the suspended threads whose pagers have resumed them. Returns whether one of
+    /// them (or any other ready thread) outranks the running one, which should then yield.
+    pub fn wake_resumed(&mut self, status: impl Fn(TaskId) -> FaultStatus) -> bool {
+        self.release(status);
+        self.should_preempt()
+    }
+
+    /// Whether a ready thread outranks the running one, which should then yield.
+    pub fn should_preempt(&self) -> bool {
+        let Some(cur) = self.current.and_then(|c| self.tcbs[c.index()]) else {
+            return false;
+        };
+        cur.state != ThreadState::Running
+            || self
+                .highest_ready()
+                .is_some_and(|p| p > cur.priority as usize)
+    }
+
+    pub fn current(&self) -> Option<ThreadId> {
+        self.current
+    }
+
+    pub fn get(&self, id: ThreadId) -> Option<&Tcb> {
+        self.tcbs.get(id.index())?.as_ref()
+    }
+
+    /// Pick the first thread to run and hand it to the arch. Returns only if there is
+    /// none (or no arch to run it).
+    pub fn start(&mut self) {
+        let Some(arch) = self.arch else {
+            return;
+        };
+        if let Some(ctx) = self.switch(|_| FaultStatus::Resumed, false) {
+            arch.start(ctx.0);
+        }
+    }
+
+    /// Called on every timer tick. Wakes the sleepers that are due and returns the
+    /// context to resume: the interrupted thread's, unless a higher-priority thread is
+    /// ready or the slice is used up and another thread of the same priority is ready.
+    /// `None` before `start`, when the interrupted code isn't a thread.
+    ///
+    /// `status(task)` says where a suspended thread's fault stands: it stays off the CPU
+    /// until resumed, and is reaped once killed.
+    pub fn tick(&mut self, status: impl Fn(TaskId) -> FaultStatus) -> Option<ArchContext> {
+        self.ticks = self.ticks.wrapping_add(1);
+        let cur = self.current?;
+        let now = self.now();
+        while let Some(id) = self.sleepers.pop_due(now) {
+            self.enqueue(id, false);
+        }
+        self.release(&status);
+        self.slice_left = self.slice_left.saturating_sub(1);
+        if self.should_preempt() {
+            // Back to the head of its queue, to finish its turn once the CPU is free.
+            return self.switch(status, true);
+        }
+        if self.slice_left > 0 {
+            return self.tcbs[cur.index()].map(|t| t.context);
+        }
+        self.switch(status, false)
+    }
+
+    /// Take the current thread off the CPU after a fault and return the context to run
+    /// instead, or `None` if no other thread can run.
+    pub fn suspend_current(
+        &mut self,
+        status: impl Fn(TaskId) -> FaultStatus,
+    ) -> Option<ArchContext> {
+        let cur = self.current?;
+        if let Some(t) = &mut self.tcbs[cur.index()] {
+            t.state = ThreadState::Suspended;
+            self.suspended |= 1 << cur.index();
+        }
+        self.switch(status, false)
+    }
+
+    /// Requeue the current thread if it is still running (at the head of its queue if
+    /// `preempted`, else at the tail) and run the first thread of the highest non-empty
+    /// queue, with a fresh slice. `None` if nothing is ready.
+    fn switch(
+        &mut self,
+        status: impl Fn(TaskId) -> FaultStatus,
+        preempted: bool,
+    ) -> Option<ArchContext> {
+        // The outgoing thread may still be on its stack (we can be called from its
+        // trap), so only threads that died earlier are reaped here.
+        self.release(&status);
+        self.reap(self.current);
+        if let Some(cur) = self.current {
+            if self.tcbs[cur.index()].is_some_and(|t| t.state == ThreadState::Running) {
+                self.enqueue(cur, preempted);
+            }
+        }
+        let prio = self.highest_ready()?;
+        let next = self.queues[prio].pop_front()?;
+        if self.queues[prio].len == 0 {
+            self.ready &= !(1 << prio);
+        }
+        let t = self.tcbs[next.index()].as_mut()?;
+        t.state = ThreadState::Running;
+        self.current = Some(next);
+        self.slice_left = self.slices[prio];
+        Some(t.context)
+    }
+
+    fn highest_ready(&self) -> Option<usize> {
+        (self.ready != 0).then(|| 31 - self.ready.leading_zeros() as usize)
+    }
+
+    fn enqueue(&mut self, id: ThreadId, front: bool) {
+        let Some(t) = &mut self.tcbs[id.index()] else {
+            return;
+        };
+        t.state = ThreadState::Ready;
+        let prio = t.priority as usize;
+        if front {
+            self.queues[prio].push_front(id);
+        } else {
+            self.queues[prio].push_back(id);
+        }
+        self.ready |= 1 << prio;
+    }
+
+    /// Queue the suspended threads that were resumed, and mark the killed ones dead so
+    /// they get reaped.
+    fn release(&mut self, status: impl Fn(TaskId) -> FaultStatus) {
+        let mut pending = self.suspended;
+        while pending != 0 {
+            let i = pending.trailing_zeros() as usize;
+            pending &= pending - 1;
+            let id = ThreadId::new(i);
+            // Only threads with a task are suspended; anything else has no pager to wait for.
+            let task = self.tcbs[i].and_then(|t| t.task);
+            match task.map_or(FaultStatus::Killed, &status) {
+                FaultStatus::Suspended => continue,
+                FaultStatus::Resumed => self.enqueue(id, false),
+                FaultStatus::Killed => {
+                    if let Some(t) = &mut self.tcbs[i] {
+                        t.state = ThreadState::Dead;
+                    }
+                }
+            }
+            self.suspended &= !(1 << i);
+        }
+    }
+
+    /// Free the stacks and slots of dead threads other than `keep`.
+    fn reap(&mut self, keep: Option<ThreadId>) {
+        for (i, slot) in self.tcbs.iter_mut().enumerate() {
+            let Some(t) = slot else {
+                continue;
+            };
+            if t.state != ThreadState::Dead || keep == Some(ThreadId::new(i)) {
+                continue;
+            }
+            if let Some(frames) = self.frames {
+                frames.free_frames(t.stack.0, t.stack.1);
+            }
+            *slot = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::testutil::frames;
+
+    /// Hands out the stack's address as the context, so tests can tell threads apart.
+    struct MockArch;
+
+    impl ThreadArch for MockArch {
+        fn init(&self, stack: &'static mut [u8], _entry: ThreadEntry, _arg: usize) -> usize {
+            stack.as_ptr() as usize
+        }
+
+        fn start(&self, _ctx: usize) -> ! {
+            unreachable!("tests never start threads")
+        }
+
+        fn yield_now(&self) {}
+    }
+
+    extern "C" fn idle(_arg: usize) -> ! {
+        unreachable!()
+    }
+
+    fn spawn(threads: &mut Threads) -> ArchContext {
+        spawn_at(threads, 0)
+    }
+
+    fn spawn_at(threads: &mut Threads, priority: u8) -> ArchContext {
+        let id = threads.spawn(idle, 0, 1, priority).unwrap();
+        threads.set_task(id, TaskId::new(id.index())).unwrap();
+        threads.get(id).unwrap().context
+    }
+
+    fn setup(n: usize) -> (Threads, Vec<ArchContext>) {
+        let mut threads = Threads::new();
+        threads.set_arch(&MockArch);
+        threads.set_frame_allocator(frames(usize::MAX));
+        let ctxs = (0..n).map(|_| spawn(&mut threads)).collect();
+        (threads, ctxs)
+    }
+
+    fn no_faults(_: TaskId) -> FaultStatus {
+        FaultStatus::Resumed
+    }
+
+    fn run_slice(
+        threads: &mut Threads,
+        status: impl Fn(TaskId) -> FaultStatus,
+    ) -> Option<ArchContext> {
+        (0..TIME_SLICE).map(|_| threads.tick(&status)).last()?
+    }
+
+    #[test]
+    fn round_robin_per_slice() {
+        let (mut threads, ctxs) = setup(3);
+        assert_eq!(threads.tick(no_faults), None);
+        assert_eq!(threads.switch(no_faults, false), Some(ctxs[0]));
+
+        for _ in 0..TIME_SLICE - 1 {
+            assert_eq!(threads.tick(no_faults), Some(ctxs[0]));
+        }
+        assert_eq!(threads.tick(no_faults), Some(ctxs[1]));
+        assert_eq!(
+            threads.get(ThreadId::new(0)).unwrap().state,
+            ThreadState::Ready
+        );
+        assert_eq!(run_slice(&mut threads, no_faults), Some(ctxs[2]));
+        assert_eq!(run_slice(&mut threads, no_faults), Some(ctxs[0]));
+    }
+
+    #[test]
+    fn suspended_threads_wait_until_released() {
+        let (mut threads, ctxs) = setup(2);
+        threads.switch(no_faults, false);
+        let fault = Cell::new(FaultStatus::Suspended);
+        let status = |task: TaskId| match task.index() {
+            0 => fault.get(),
+            _ => FaultStatus::Resumed,
+        };
+
+        assert_eq!(threads.suspend_current(status), Some(ctxs[1]));
+        assert_eq!(run_slice(&mut threads, status), Some(ctxs[1]));
+        assert_eq!(
+            threads.get(ThreadId::new(0)).unwrap().state,
+            ThreadState::Suspended
+        );
+
+        fault.set(FaultStatus::Resumed);
+        assert_eq!(run_slice(&mut threads, status), Some(ctxs[0]));
+        assert_eq!(
+            threads.suspend_current(|_| FaultStatus::Suspended),
+            Some(ctxs[1])
+        );
+        assert_eq!(threads.suspend_current(|_| FaultStatus::Suspended), None);
+    }
+
+    #[test]
+    fn killed_threads_are_reaped() {
+        let mut threads = Threads::new();
+        threads.set_arch(&MockArch);
+        let allocator = frames(usize::MAX);
+        threads.set_frame_allocator(allocator);
+        let ctxs: Vec<_> = (0..2).map(|_| spawn(&mut threads)).collect();
+        threads.switch(no_faults, false);
+
+        let fault = Cell::new(FaultStatus::Suspended);
+        let status = |_: TaskId| fault.get();
+        assert_eq!(threads.suspend_current(status), Some(ctxs[1]));
+        fault.set(FaultStatus::Killed);
+        assert_eq!(threads.yield_current(status), Some(ctxs[1]));
+        assert!(threads.get(ThreadId::new(0)).is_none());
+        assert_eq!(allocator.freed(), [(ctxs[0].0 as u64, 1)]);
+        assert_eq!(
+            threads.set_task(ThreadId::new(0), TaskId::new(0)),
+            Err(ThreadError::NoSuchThread)
+        );
+    }
+
+    #[test]
+    fn spawn_needs_arch_allocator_and_room() {
+        let mut threads = Threads::new();
+        threads.set_arch(&MockArch);
+        assert_eq!(threads.spawn(idle, 0, 1, 0), Err(ThreadError::Unsupported));
+        threads.set_frame_allocator(frames(2));
+        threads.spawn(idle, 0, PAGE_SIZE as usize + 1, 0).unwrap();
+        assert_eq!(threads.spawn(idle, 0, 1, 0), Err(ThreadError::OutOfMemory));
+
+        let (mut threads, _) = setup(MAX_THREADS);
+        assert_eq!(threads.spawn(idle, 0, 1, 0), Err(ThreadError::TableFull));
+    }
+
+    #[test]
+    fn higher_priority_runs_first_and_preempts() {
+        let (mut threads, low) = setup(2);
+        let high = spawn_at(&mut threads, 3);
+        assert_eq!(threads.switch(no_faults, false), Some(high));
+        for _ in 0..3 * TIME_SLICE {
+            assert_eq!(threads.tick(no_faults), Some(high));
+        }
+
+        // Lower priorities only run while the higher one can't.
+        let fault = Cell::new(FaultStatus::Suspended);
+        let status = |task: TaskId| match task.index() {
+            2 => fault.get(),
+            _ => FaultStatus::Resumed,
+        };
+        assert_eq!(threads.suspend_current(status), Some(low[0]));
+        assert_eq!(run_slice(&mut threads, status), Some(low[1]));
+
+        // Released: takes over on the next tick, and the preempted thread resumes
+        // before its peer.
+        fault.set(FaultStatus::Resumed);
+        assert_eq!(threads.tick(status), Some(high));
+        threads.exit_current();
+        assert_eq!(threads.yield_current(status), Some(low[1]));
+
+        let mid = spawn_at(&mut threads, 1);
+        assert!(threads.should_preempt());
+        assert_eq!(threads.yield_current(status), Some(mid));
+        assert!(!threads.should_preempt());
+    }
+
+    #[test]
+    fn resumed_thread_preempts_before_the_next_tick() {
+        let (mut threads, low) = setup(1);
+        let high = spawn_at(&mut threads, 2);
+        threads.switch(no_faults, false);
+        let fault = Cell::new(FaultStatus::Suspended);
+        let status = |task: TaskId| match task.index() {
+            1 => fault.get(),
+            _ => FaultStatus::Resumed,
+        };
+        assert_eq!(threads.suspend_current(status), Some(low[0]));
+        assert!(!threads.wake_resumed(status));
+
+        fault.set(FaultStatus::Resumed);
+        assert!(threads.wake_resumed(status));
+        assert_eq!(
+            threads.get(ThreadId::new(1)).unwrap().state,
+            ThreadState::Ready
+        );
+        assert_eq!(threads.yield_current(status), Some(high));
+    }
+
+    #[test]
+    fn time_slice_is_per_priority() {
+        let (mut threads, _) = setup(0);
+        let ctxs = [spawn_at(&mut threads, 2), spawn_at(&mut threads, 2)];
+        spawn_at(&mut threads, 0);
+        threads.set_time_slice(2, 2).unwrap();
+        assert_eq!(
+            threads.set_time_slice(PRIORITIES as u8, 1),
+            Err(ThreadError::BadPriority)
+        );
+        assert_eq!(
+            threads.spawn(idle, 0, 1, PRIORITIES as u8),
+            Err(ThreadError::BadPriority)
+        );
+
+        threads.switch(no_faults, false);
+        for expected in [0, 1, 1, 0, 0, 1] {
+            assert_eq!(threads.tick(no_faults), Some(ctxs[expected]));
+        }
+    }
+
+    #[test]
+    fn sleepers_wake_in_deadline_order() {
+        let (mut threads, ctxs) = setup(4);
+        threads.switch(no_faults, false);
+        assert!(!threads.sleep_current(0));
+
+        // Threads 0..3 sleep until ticks 3, 2 and 2, in that order.
+        for deadline in [3, 2, 2] {
+            assert!(threads.sleep_current(deadline));
+            assert!(threads.current_sleeping());
+            threads.yield_current(no_faults);
+        }
+        assert_eq!(threads.current(), Some(ThreadId::new(3)));
+        threads.set_time_slice(0, 100).unwrap();
+        assert!(threads.sleep_current(5));
+        assert_eq!(threads.yield_current(no_faults), None);
+
+        // Thread 3 keeps the CPU while it can't run, but is switched away from as soon
+        // as anything wakes.
+        assert_eq!(threads.tick(no_faults), None);
+        assert_eq!(threads.tick(no_faults), Some(ctxs[1]));
+        assert_eq!(threads.yield_current(no_faults), Some(ctxs[2]));
+        assert_eq!(threads.tick(no_faults), Some(ctxs[2]));
+        assert_eq!(threads.yield_current(no_faults), Some(ctxs[1]));
+        assert_eq!(threads.yield_current(no_faults), Some(ctxs[0]));
+
+        assert_eq!(threads.tick(no_faults), Some(ctxs[0]));
+        assert_eq!(threads.tick(no_faults), Some(ctxs[0]));
+        assert_eq!(threads.now(), 5);
+        for expected in [2, 1, 3] {
+            assert_eq!(threads.yield_current(no_faults), Some(ctxs[expected]));
+        }
+    }
+
+    #[test]
+    fn exited_threads_are_reaped_after_switching_away() {
+        let alloc = frames(usize::MAX);
+        let mut threads = Threads::new();
+        threads.set_arch(&MockArch);
+        threads.set_frame_allocator(alloc);
+        let ctxs: Vec<_> = (0..2).map(|_| spawn(&mut threads)).collect();
+        threads.switch(no_faults, false);
+        let stack = threads.get(ThreadId::new(0)).unwrap().stack;
+
+        threads.exit_current();
+        assert_eq!(threads.yield_current(no_faults), Some(ctxs[1]));
+        assert_eq!(
+            threads.get(ThreadId::new(0)).unwrap().state,
+            ThreadState::Dead
+        );
+        assert!(alloc.freed().is_empty());
+
+        assert_eq!(run_slice(&mut threads, no_faults), Some(ctxs[1]));
+        assert!(threads.get(ThreadId::new(0)).is_none());
+        assert_eq!(alloc.freed(), [stack]);
+
+        assert_eq!(threads.spawn(idle, 0, 1, 0), Ok(ThreadId::new(0)));
+        let fresh = threads.get(ThreadId::new(0)).unwrap().context;
+        threads.exit_current();
+        assert_eq!(threads.yield_current(no_faults), Some(fresh));
+        threads.exit_current();
+        assert_eq!(threads.yield_current(no_faults), None);
+    }
+}
+
+